@@ -0,0 +1,334 @@
+use libp2p::PeerId;
+use std::collections::{HashMap, HashSet};
+
+/// Maximum number of ranges accepted in a single announcement.
+const MAX_RANGES: usize = 1024;
+/// Maximum number of segment ids that are not sequence numbers in a single announcement.
+const MAX_IDS: usize = 1024;
+/// Same bound as segment requests, longer ids are garbage.
+const MAX_ID_LEN: usize = 1024;
+
+/// Set of segment sequence numbers, kept as sorted, disjoint and non adjacent inclusive ranges.
+///
+/// Peers hold a sliding window of a live stream, which usually fits in one or two ranges.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RangeSet {
+    ranges: Vec<(u64, u64)>,
+}
+
+impl RangeSet {
+    pub fn insert(&mut self, sequence: u64) {
+        // First range ending at or after the sequence (or right before it).
+        let index = self
+            .ranges
+            .partition_point(|(_, end)| end.saturating_add(1) < sequence);
+        match self.ranges.get_mut(index) {
+            Some((start, end)) if *start <= sequence.saturating_add(1) => {
+                *start = (*start).min(sequence);
+                *end = (*end).max(sequence);
+                // Extending the end may have joined the next range.
+                if let Some(&(next_start, next_end)) = self.ranges.get(index + 1)
+                    && next_start <= self.ranges[index].1.saturating_add(1)
+                {
+                    self.ranges[index].1 = next_end;
+                    self.ranges.remove(index + 1);
+                }
+            }
+            _ => self.ranges.insert(index, (sequence, sequence)),
+        }
+    }
+
+    pub fn contains(&self, sequence: u64) -> bool {
+        let index = self.ranges.partition_point(|(_, end)| *end < sequence);
+        self.ranges
+            .get(index)
+            .is_some_and(|(start, _)| *start <= sequence)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Encode as `u32 range count | (u64 first | u64 last)*`, big-endian.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.ranges.len() * 16);
+        bytes.extend_from_slice(&(self.ranges.len() as u32).to_be_bytes());
+        for (start, end) in &self.ranges {
+            bytes.extend_from_slice(&start.to_be_bytes());
+            bytes.extend_from_slice(&end.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Decode a range set, rejecting overlapping or unsorted ranges.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (count, rest) = bytes.split_first_chunk::<4>()?;
+        let count = u32::from_be_bytes(*count) as usize;
+        if count > MAX_RANGES || rest.len() != count * 16 {
+            return None;
+        }
+        let mut ranges: Vec<(u64, u64)> = Vec::with_capacity(count);
+        for range in rest.as_chunks::<16>().0 {
+            let (start, end) = range.split_at(8);
+            let start = u64::from_be_bytes(start.try_into().ok()?);
+            let end = u64::from_be_bytes(end.try_into().ok()?);
+            let follows = ranges
+                .last()
+                .is_none_or(|(_, previous)| start > previous.saturating_add(1));
+            if start > end || !follows {
+                return None;
+            }
+            ranges.push((start, end));
+        }
+        Some(RangeSet { ranges })
+    }
+}
+
+/// Segments held by a peer: HLS sequence numbers as ranges, any other segment id one by one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Holdings {
+    sequences: RangeSet,
+    ids: HashSet<String>,
+}
+
+impl Holdings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, segment_id: &str) {
+        match segment_id.parse() {
+            Ok(sequence) => self.sequences.insert(sequence),
+            Err(_) => {
+                self.ids.insert(segment_id.to_string());
+            }
+        }
+    }
+
+    pub fn contains(&self, segment_id: &str) -> bool {
+        match segment_id.parse() {
+            Ok(sequence) => self.sequences.contains(sequence),
+            Err(_) => self.ids.contains(segment_id),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty() && self.ids.is_empty()
+    }
+
+    /// Encode as the range set of the sequence numbers followed by `u32 id count | (u32 len |
+    /// segment id)*`, big-endian, ids in lexicographic order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.sequences.encode();
+        bytes.extend_from_slice(&(self.ids.len() as u32).to_be_bytes());
+        // Sorted, so that the same holdings are always encoded the same way.
+        let mut ids: Vec<&String> = self.ids.iter().collect();
+        ids.sort();
+        for id in ids {
+            bytes.extend_from_slice(&(id.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(id.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (count, _) = bytes.split_first_chunk::<4>()?;
+        let count = u32::from_be_bytes(*count) as usize;
+        // Checked first, the length below would overflow on wasm32.
+        if count > MAX_RANGES {
+            return None;
+        }
+        let ranges_len = 4 + count * 16;
+        let (sequences, rest) = bytes.split_at_checked(ranges_len)?;
+        let sequences = RangeSet::decode(sequences)?;
+
+        let (count, mut rest) = rest.split_first_chunk::<4>()?;
+        let count = u32::from_be_bytes(*count) as usize;
+        if count > MAX_IDS {
+            return None;
+        }
+        let mut ids = HashSet::with_capacity(count);
+        for _ in 0..count {
+            let (len, tail) = rest.split_first_chunk::<4>()?;
+            let len = u32::from_be_bytes(*len) as usize;
+            if len > MAX_ID_LEN {
+                return None;
+            }
+            let (id, tail) = tail.split_at_checked(len)?;
+            ids.insert(String::from_utf8(id.to_vec()).ok()?);
+            rest = tail;
+        }
+        rest.is_empty().then_some(Holdings { sequences, ids })
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Holdings {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(segment_ids: I) -> Self {
+        let mut holdings = Holdings::new();
+        for segment_id in segment_ids {
+            holdings.insert(segment_id);
+        }
+        holdings
+    }
+}
+
+/// Which segments the other peers announced they hold.
+#[derive(Debug, Default)]
+pub struct Availability {
+    peers: HashMap<PeerId, Holdings>,
+}
+
+impl Availability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace what `peer` holds with its latest announcement.
+    pub fn update(&mut self, peer: PeerId, held: Holdings) {
+        if held.is_empty() {
+            self.peers.remove(&peer);
+        } else {
+            self.peers.insert(peer, held);
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Peers that announced holding the segment.
+    pub fn holders(&self, segment_id: &str) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, held)| held.contains(segment_id))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(set: &RangeSet) -> &[(u64, u64)] {
+        &set.ranges
+    }
+
+    fn encode_ranges(ranges: &[(u64, u64)]) -> Vec<u8> {
+        RangeSet {
+            ranges: ranges.to_vec(),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn merges_adjacent_sequences() {
+        let mut set = RangeSet::default();
+        for sequence in [5, 7, 1, 6, 2, u64::MAX] {
+            set.insert(sequence);
+        }
+        assert_eq!(ranges(&set), [(1, 2), (5, 7), (u64::MAX, u64::MAX)]);
+        assert!(set.contains(6));
+        assert!(!set.contains(3));
+
+        set.insert(3);
+        set.insert(4);
+        assert_eq!(ranges(&set), [(1, 7), (u64::MAX, u64::MAX)]);
+    }
+
+    #[test]
+    fn round_trips_range_sets() {
+        let mut set = RangeSet::default();
+        for sequence in [0, 1, 2, 10, 42, u64::MAX] {
+            set.insert(sequence);
+        }
+        assert_eq!(RangeSet::decode(&set.encode()), Some(set));
+        assert_eq!(
+            RangeSet::decode(&RangeSet::default().encode()),
+            Some(RangeSet::default())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_range_sets() {
+        // Truncated, or trailing bytes.
+        assert_eq!(RangeSet::decode(&[0, 0, 0]), None);
+        let mut bytes = encode_ranges(&[(1, 2)]);
+        bytes.pop();
+        assert_eq!(RangeSet::decode(&bytes), None);
+        let mut bytes = encode_ranges(&[(1, 2)]);
+        bytes.push(0);
+        assert_eq!(RangeSet::decode(&bytes), None);
+
+        // Reversed, overlapping, adjacent and unsorted ranges.
+        for invalid in [
+            &[(2, 1)][..],
+            &[(1, 5), (3, 8)],
+            &[(1, 2), (3, 4)],
+            &[(5, 6), (1, 2)],
+        ] {
+            assert_eq!(
+                RangeSet::decode(&encode_ranges(invalid)),
+                None,
+                "{invalid:?}"
+            );
+        }
+
+        // Too many ranges.
+        let many: Vec<(u64, u64)> = (0..=MAX_RANGES as u64).map(|i| (2 * i, 2 * i)).collect();
+        assert_eq!(RangeSet::decode(&encode_ranges(&many)), None);
+    }
+
+    #[test]
+    fn round_trips_holdings() {
+        let holdings: Holdings = ["init", "10", "11", "13", "key.bin"].into_iter().collect();
+        let decoded = Holdings::decode(&holdings.encode()).unwrap();
+        assert_eq!(decoded, holdings);
+        assert!(decoded.contains("11"));
+        assert!(decoded.contains("init"));
+        assert!(!decoded.contains("12"));
+
+        assert_eq!(
+            Holdings::decode(&Holdings::new().encode()),
+            Some(Holdings::new())
+        );
+    }
+
+    #[test]
+    fn encodes_holdings_deterministically() {
+        let ids = ["init", "key.bin", "audio", "10", "b", "a"];
+        let holdings: Holdings = ids.into_iter().collect();
+        let reversed: Holdings = ids.into_iter().rev().collect();
+        assert_eq!(holdings.encode(), reversed.encode());
+    }
+
+    #[test]
+    fn rejects_invalid_holdings() {
+        let holdings: Holdings = ["init", "10"].into_iter().collect();
+        let bytes = holdings.encode();
+        for len in 0..bytes.len() {
+            assert_eq!(Holdings::decode(&bytes[..len]), None, "truncated to {len}");
+        }
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Holdings::decode(&trailing), None);
+
+        // A range count far beyond the data.
+        assert_eq!(
+            Holdings::decode(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]),
+            None
+        );
+
+        // Ids that are too long or not UTF-8.
+        let mut long_id = RangeSet::default().encode();
+        long_id.extend_from_slice(&1u32.to_be_bytes());
+        long_id.extend_from_slice(&(MAX_ID_LEN as u32 + 1).to_be_bytes());
+        long_id.extend(std::iter::repeat_n(b'a', MAX_ID_LEN + 1));
+        assert_eq!(Holdings::decode(&long_id), None);
+        let mut not_utf8 = RangeSet::default().encode();
+        not_utf8.extend_from_slice(&1u32.to_be_bytes());
+        not_utf8.extend_from_slice(&1u32.to_be_bytes());
+        not_utf8.push(0xff);
+        assert_eq!(Holdings::decode(&not_utf8), None);
+    }
+}
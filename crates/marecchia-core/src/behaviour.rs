@@ -1,27 +1,59 @@
-use std::time::Duration;
+use std::{convert::Infallible, error::Error, time::Duration};
 
 use libp2p::{
-    autonat,
-    gossipsub::{self, MessageAuthenticity},
+    allow_block_list::{self, BlockedPeers},
+    autonat, dcutr,
+    gossipsub::{
+        self, ConfigBuilderError, MessageAuthenticity, PeerScoreParams, PeerScoreThresholds,
+        ValidationMode,
+    },
     identify,
     identity::Keypair,
     ping, relay,
     rendezvous::client as rendezvous,
+    request_response::{self, ProtocolSupport},
     swarm::NetworkBehaviour,
 };
 
+use super::config::P2PConfig;
+use super::protocol::{SEGMENT_PROTOCOL, SegmentCodec, SegmentRequest, SegmentResponse};
+
 #[derive(NetworkBehaviour)]
 #[behaviour(to_swarm = "ComposedSwarmEvent")]
 pub struct ComposedSwarmBehaviour {
+    pub blocked: allow_block_list::Behaviour<BlockedPeers>,
     pub ping: ping::Behaviour,
+    pub autonat: autonat::Behaviour,
+    pub dcutr: dcutr::Behaviour,
     pub identify: identify::Behaviour,
     pub rendezvous: rendezvous::Behaviour,
     pub relay: relay::client::Behaviour,
     pub pubsub: gossipsub::Behaviour,
+    pub segment: request_response::Behaviour<SegmentCodec>,
+}
+
+/// Gossipsub configuration of the stream topic, tuned from the client options.
+pub fn gossipsub_config(config: &P2PConfig) -> Result<gossipsub::Config, ConfigBuilderError> {
+    let (mesh_n_low, mesh_n, mesh_n_high) = config.mesh_size();
+    gossipsub::ConfigBuilder::default()
+        .mesh_n_low(mesh_n_low)
+        .mesh_n(mesh_n)
+        .mesh_n_high(mesh_n_high)
+        .mesh_outbound_min((mesh_n / 2).min(mesh_n_low))
+        .heartbeat_interval(config.heartbeat_interval())
+        .max_transmit_size(config.max_transmit_size())
+        .validation_mode(ValidationMode::Strict)
+        // Announcements are forwarded only once the event loop accepted them.
+        .validate_messages()
+        .build()
 }
 
 impl ComposedSwarmBehaviour {
-    pub fn new(keypair: &Keypair, relay_behaviour: relay::client::Behaviour) -> Self {
+    pub fn new(
+        keypair: &Keypair,
+        relay_behaviour: relay::client::Behaviour,
+        gossipsub_config: gossipsub::Config,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
         let peer_id = keypair.public().to_peer_id();
         // Define the various behaviours of the swarm.
         let ping_config = ping::Config::new()
@@ -29,40 +61,69 @@ impl ComposedSwarmBehaviour {
             .with_timeout(Duration::from_secs(10));
         let ping = ping::Behaviour::new(ping_config);
 
+        // The trackers are added as servers, as browser peers cannot dial back.
         let autonat_config = autonat::Config::default();
-        let _autonat = autonat::Behaviour::new(peer_id, autonat_config);
+        let autonat = autonat::Behaviour::new(peer_id, autonat_config);
+        // Attempt to upgrade connections relayed by the trackers to direct ones. In browsers this
+        // never succeeds: libp2p-webrtc-websys 0.4 cannot listen and refuses to dial as the
+        // listener of a hole punch, so every attempt fails. Kept for when it can.
+        let dcutr = dcutr::Behaviour::new(peer_id);
 
         let identify_config =
             identify::Config::new("/marecchia-identify/0.0.1".to_string(), keypair.public());
         let identify = identify::Behaviour::new(identify_config);
 
         let rendezvous = rendezvous::Behaviour::new(keypair.to_owned());
-        // TODO: FINISH CONFIG
-        let gossipsub_config = gossipsub::Config::default();
-        let pubsub = gossipsub::Behaviour::new(
+        let mut pubsub = gossipsub::Behaviour::new(
             MessageAuthenticity::Signed(keypair.to_owned()),
             gossipsub_config,
-        )
-        .unwrap();
+        )?;
+        // The application score is the reputation earned serving segments, taken as is.
+        let score_params = PeerScoreParams {
+            app_specific_weight: 1.0,
+            ..Default::default()
+        };
+        pubsub.with_peer_score(score_params, PeerScoreThresholds::default())?;
+
+        let segment_config =
+            request_response::Config::default().with_request_timeout(Duration::from_secs(10));
+        let segment = request_response::Behaviour::new(
+            [(SEGMENT_PROTOCOL, ProtocolSupport::Full)],
+            segment_config,
+        );
 
-        Self {
+        Ok(Self {
+            blocked: allow_block_list::Behaviour::default(),
             ping,
-            //autonat,
+            autonat,
+            dcutr,
             identify,
             pubsub,
             rendezvous,
             relay: relay_behaviour,
-        }
+            segment,
+        })
     }
 }
 
+// Events are moved out of the swarm one at a time, boxing the large ones is not worth it.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum ComposedSwarmEvent {
     Ping(ping::Event),
+    Autonat(autonat::Event),
+    Dcutr(dcutr::Event),
     Identify(identify::Event),
     Rendezvous(rendezvous::Event),
     Relay(relay::client::Event),
     Gossipsub(gossipsub::Event),
+    Segment(request_response::Event<SegmentRequest, SegmentResponse>),
+}
+
+impl From<Infallible> for ComposedSwarmEvent {
+    fn from(event: Infallible) -> Self {
+        match event {}
+    }
 }
 
 impl From<ping::Event> for ComposedSwarmEvent {
@@ -71,6 +132,18 @@ impl From<ping::Event> for ComposedSwarmEvent {
     }
 }
 
+impl From<autonat::Event> for ComposedSwarmEvent {
+    fn from(event: autonat::Event) -> Self {
+        ComposedSwarmEvent::Autonat(event)
+    }
+}
+
+impl From<dcutr::Event> for ComposedSwarmEvent {
+    fn from(event: dcutr::Event) -> Self {
+        ComposedSwarmEvent::Dcutr(event)
+    }
+}
+
 impl From<identify::Event> for ComposedSwarmEvent {
     fn from(event: identify::Event) -> Self {
         ComposedSwarmEvent::Identify(event)
@@ -94,3 +167,9 @@ impl From<gossipsub::Event> for ComposedSwarmEvent {
         ComposedSwarmEvent::Gossipsub(event)
     }
 }
+
+impl From<request_response::Event<SegmentRequest, SegmentResponse>> for ComposedSwarmEvent {
+    fn from(event: request_response::Event<SegmentRequest, SegmentResponse>) -> Self {
+        ComposedSwarmEvent::Segment(event)
+    }
+}
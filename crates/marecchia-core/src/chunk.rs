@@ -0,0 +1,272 @@
+use std::fmt;
+
+/// Size of the chunks segments are split into when transferred between peers.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// A slice of a segment together with the header needed to put it back in place.
+#[derive(Debug, Clone)]
+pub struct Chunk {
+    pub index: u32,
+    pub total: u32,
+    pub segment_len: u32,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Cut the chunk at `index` out of a whole segment.
+    pub fn from_segment(segment: &[u8], index: u32) -> Option<Self> {
+        let total = chunk_count(segment.len());
+        if index >= total {
+            return None;
+        }
+        let start = index as usize * CHUNK_SIZE;
+        let end = (start + CHUNK_SIZE).min(segment.len());
+        Some(Chunk {
+            index,
+            total,
+            segment_len: segment.len() as u32,
+            data: segment[start..end].to_vec(),
+        })
+    }
+}
+
+/// Number of chunks needed for a segment of `len` bytes. Empty segments still take one chunk.
+pub fn chunk_count(len: usize) -> u32 {
+    len.div_ceil(CHUNK_SIZE).max(1) as u32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The chunk header disagrees with the chunks received so far.
+    Inconsistent,
+    /// The chunk index is out of range or its size does not match its position.
+    Malformed { index: u32 },
+    /// The segment cannot be assembled yet.
+    Missing { missing: u32, total: u32 },
+}
+
+impl fmt::Display for ChunkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::Inconsistent => write!(f, "chunk header does not match previous chunks"),
+            ChunkError::Malformed { index } => write!(f, "chunk {index} is malformed"),
+            ChunkError::Missing { missing, total } => {
+                write!(f, "{missing} of {total} chunks are missing")
+            }
+        }
+    }
+}
+
+/// Collects the chunks of one segment, in any order, until it can be reassembled.
+#[derive(Debug, Default)]
+pub struct ChunkAssembler {
+    segment_len: Option<u32>,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+}
+
+impl ChunkAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a chunk, validating it against the layout announced by the first one.
+    pub fn insert(&mut self, chunk: Chunk) -> Result<(), ChunkError> {
+        match self.segment_len {
+            None => {
+                if chunk.total != chunk_count(chunk.segment_len as usize) {
+                    return Err(ChunkError::Inconsistent);
+                }
+                self.segment_len = Some(chunk.segment_len);
+                self.chunks = vec![None; chunk.total as usize];
+            }
+            Some(_) if self.conflicts(&chunk) => return Err(ChunkError::Inconsistent),
+            Some(_) => {}
+        }
+
+        let index = chunk.index as usize;
+        let start = index * CHUNK_SIZE;
+        let expected_len = (chunk.segment_len as usize)
+            .saturating_sub(start)
+            .min(CHUNK_SIZE);
+        if index >= self.chunks.len() || chunk.data.len() != expected_len {
+            return Err(ChunkError::Malformed { index: chunk.index });
+        }
+
+        if self.chunks[index].is_none() {
+            self.received += 1;
+        }
+        self.chunks[index] = Some(chunk.data);
+        Ok(())
+    }
+
+    /// Whether `chunk` describes another layout than the chunks received so far.
+    pub fn conflicts(&self, chunk: &Chunk) -> bool {
+        self.segment_len
+            .is_some_and(|len| len != chunk.segment_len || self.total() != Some(chunk.total))
+    }
+
+    /// Total number of chunks, known once the first chunk arrived.
+    pub fn total(&self) -> Option<u32> {
+        self.segment_len.map(|_| self.chunks.len() as u32)
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.total() == Some(self.received)
+    }
+
+    /// Indexes of the chunks not received yet, empty until the first chunk arrived.
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(index, _)| index as u32)
+    }
+
+    /// Concatenate the chunks into the original segment.
+    pub fn assemble(self) -> Result<Vec<u8>, ChunkError> {
+        let total = self.total().unwrap_or(0);
+        if !self.is_complete() {
+            return Err(ChunkError::Missing {
+                missing: total - self.received,
+                total,
+            });
+        }
+
+        let mut segment = Vec::with_capacity(self.segment_len.unwrap_or(0) as usize);
+        for chunk in self.chunks.into_iter().flatten() {
+            segment.extend_from_slice(&chunk);
+        }
+        Ok(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    fn chunks(segment: &[u8]) -> Vec<Chunk> {
+        (0..chunk_count(segment.len()))
+            .map(|index| Chunk::from_segment(segment, index).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn assembles_chunks_in_order() {
+        let segment = segment(2 * CHUNK_SIZE + 10);
+        let mut assembler = ChunkAssembler::new();
+        for chunk in chunks(&segment) {
+            assembler.insert(chunk).unwrap();
+        }
+
+        assert_eq!(assembler.total(), Some(3));
+        assert!(assembler.is_complete());
+        assert_eq!(assembler.assemble().unwrap(), segment);
+    }
+
+    #[test]
+    fn assembles_chunks_out_of_order() {
+        let segment = segment(2 * CHUNK_SIZE + 10);
+        let chunks = chunks(&segment);
+        let mut assembler = ChunkAssembler::new();
+        assembler.insert(chunks[2].clone()).unwrap();
+        assert_eq!(assembler.missing().collect::<Vec<_>>(), [0, 1]);
+        assembler.insert(chunks[0].clone()).unwrap();
+        // A duplicate is not counted twice.
+        assembler.insert(chunks[0].clone()).unwrap();
+        assert_eq!(assembler.received(), 2);
+        assert_eq!(assembler.missing().collect::<Vec<_>>(), [1]);
+
+        assembler.insert(chunks[1].clone()).unwrap();
+        assert_eq!(assembler.assemble().unwrap(), segment);
+    }
+
+    #[test]
+    fn refuses_to_assemble_incomplete_segments() {
+        let segment = segment(2 * CHUNK_SIZE + 10);
+        let mut assembler = ChunkAssembler::new();
+        assembler
+            .insert(Chunk::from_segment(&segment, 1).unwrap())
+            .unwrap();
+        assert_eq!(
+            assembler.assemble(),
+            Err(ChunkError::Missing {
+                missing: 2,
+                total: 3
+            })
+        );
+    }
+
+    #[test]
+    fn assembles_empty_segments() {
+        let chunk = Chunk::from_segment(&[], 0).unwrap();
+        assert_eq!(chunk.total, 1);
+        assert!(Chunk::from_segment(&[], 1).is_none());
+
+        let mut assembler = ChunkAssembler::new();
+        assembler.insert(chunk).unwrap();
+        assert_eq!(assembler.assemble().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        let segment = segment(CHUNK_SIZE + 10);
+        let chunks = chunks(&segment);
+        let mut assembler = ChunkAssembler::new();
+        assembler.insert(chunks[0].clone()).unwrap();
+
+        let out_of_range = Chunk {
+            index: 2,
+            ..chunks[1].clone()
+        };
+        assert_eq!(
+            assembler.insert(out_of_range),
+            Err(ChunkError::Malformed { index: 2 })
+        );
+        let truncated = Chunk {
+            data: chunks[1].data[1..].to_vec(),
+            ..chunks[1].clone()
+        };
+        assert_eq!(
+            assembler.insert(truncated),
+            Err(ChunkError::Malformed { index: 1 })
+        );
+        assert_eq!(assembler.received(), 1);
+    }
+
+    #[test]
+    fn rejects_inconsistent_chunks() {
+        let segment = segment(CHUNK_SIZE + 10);
+        let chunks = chunks(&segment);
+
+        // The first chunk must announce a layout matching its segment length.
+        let mut assembler = ChunkAssembler::new();
+        let wrong_total = Chunk {
+            total: 3,
+            ..chunks[0].clone()
+        };
+        assert_eq!(assembler.insert(wrong_total), Err(ChunkError::Inconsistent));
+        assert_eq!(assembler.total(), None);
+
+        // Later chunks must agree with the first one.
+        assembler.insert(chunks[0].clone()).unwrap();
+        let other_segment = Chunk {
+            segment_len: chunks[1].segment_len + 1,
+            data: vec![0; 11],
+            ..chunks[1].clone()
+        };
+        assert_eq!(
+            assembler.insert(other_segment),
+            Err(ChunkError::Inconsistent)
+        );
+    }
+}
@@ -1,45 +1,76 @@
-use js_sys::Uint8Array;
+use js_sys::{Promise, Uint8Array};
 use libp2p::{
-    PeerId, SwarmBuilder, Transport,
+    SwarmBuilder, Transport,
     core::upgrade::Version,
     futures::{
         SinkExt,
         channel::{mpsc, oneshot},
     },
-    identity,
-    multiaddr::{Multiaddr, Protocol},
-    noise,
+    identity, noise,
     rendezvous::Namespace,
     websocket_websys, yamux,
 };
 use libp2p_webrtc_websys as webrtc_websys;
-use std::{num::NonZeroU8, panic, time::Duration};
+use std::{
+    num::NonZeroU8,
+    panic,
+    sync::{
+        Once,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::Duration,
+};
 use tracing_subscriber::{fmt::format::Pretty, prelude::*};
 use tracing_web::{MakeWebConsoleWriter, performance_layer};
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+use web_time::Instant;
 
 use super::{
-    behaviour::ComposedSwarmBehaviour,
+    behaviour::{ComposedSwarmBehaviour, gossipsub_config},
+    config::P2PConfig,
     event_loop::{Command, EventLoop},
+    integrity::parse_digest,
 };
 
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static INIT_LOGGING: Once = Once::new();
+/// Identifiers of the segment requests, see `P2PClient::new_request_id`.
+static NEXT_REQUEST_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Report panics and tracing events to the browser console, once for all the clients created
+/// in the page.
+fn init_logging() {
+    INIT_LOGGING.call_once(|| {
+        panic::set_hook(Box::new(console_error_panic_hook::hook));
+        let fmt_layer = tracing_subscriber::fmt::layer()
+            .with_ansi(false) // Only partially supported across browsers
+            .without_time() // std::time is not available in browsers, see note below
+            .with_writer(MakeWebConsoleWriter::new()); // write events to the console
+        let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
+
+        // Fails if the page already installed a global subscriber, which then gets the events.
+        let _ = tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(perf_layer)
+            .try_init(); // Install these as subscribers to tracing events
+    });
+}
+
 #[wasm_bindgen]
-pub fn new_p2p_client(stream_namespace: String) -> Result<P2PClient, JsError> {
-    panic::set_hook(Box::new(console_error_panic_hook::hook));
-    let fmt_layer = tracing_subscriber::fmt::layer()
-        .with_ansi(false) // Only partially supported across browsers
-        .without_time() // std::time is not available in browsers, see note below
-        .with_writer(MakeWebConsoleWriter::new()); // write events to the console
-    let perf_layer = performance_layer().with_details_from_fields(Pretty::default());
-
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(perf_layer)
-        .init(); // Install these as subscribers to tracing events
+pub fn new_p2p_client(stream_namespace: String, config: &P2PConfig) -> Result<P2PClient, JsError> {
+    init_logging();
 
     let namespace = Namespace::new(stream_namespace)?;
+    if config.trackers().is_empty() {
+        return Err(JsError::new("At least one tracker must be configured"));
+    }
     tracing::info!("Starting P2P client with stream namespace: {:?}", namespace);
 
+    let gossipsub_config = gossipsub_config(config)
+        .map_err(|e| JsError::new(&format!("Invalid gossipsub configuration: {e}")))?;
+
     // Create a public/private key pair, either random or based on a seed.
     let keypair = identity::Keypair::generate_ed25519();
     tracing::debug!("Peer ID: {:?}", keypair.public().to_peer_id());
@@ -59,7 +90,7 @@ pub fn new_p2p_client(stream_namespace: String) -> Result<P2PClient, JsError> {
         .with_relay_client(|key: &_| noise::Config::new(key), yamux::Config::default)?
         // TODO: implement bandwidth metrics
         //.with_bandwidth_metrics(...)
-        .with_behaviour(ComposedSwarmBehaviour::new)?
+        .with_behaviour(|key, relay| ComposedSwarmBehaviour::new(key, relay, gossipsub_config))?
         .with_swarm_config(|c| {
             c.with_max_negotiating_inbound_streams(16)
                 .with_idle_connection_timeout(Duration::from_secs(60))
@@ -67,25 +98,28 @@ pub fn new_p2p_client(stream_namespace: String) -> Result<P2PClient, JsError> {
         })
         .build();
 
-    // Listen for inbound connections
-    //let addr = Multiaddr::empty().with(Protocol::WebRTCDirect);
-    //swarm.listen_on(addr).map_err(|e| ClientError::ListenError);
-    //tracing::info!("Listening on {:?}", addr);
+    // Browsers cannot accept inbound connections, not even over WebRTC: the websys transport
+    // only dials. Other peers reach us through the circuits reserved on the tracker relays once
+    // the event loop connects to them.
 
     let (command_send, command_recv) = mpsc::channel(20);
 
     tracing::info!("P2P client started");
 
-    let rendezvous_id = PeerId::random();
-    let rendezvous_addr = Multiaddr::empty()
-        .with(Protocol::Dns("rendezvous.marecchia.io".into()))
-        .with(Protocol::P2p(rendezvous_id));
-
-    tracing::info!("Dialing rendezvous server at {:?}", rendezvous_addr);
-    swarm.dial(rendezvous_addr)?;
+    for tracker in config.trackers() {
+        tracing::info!("Dialing rendezvous server at {:?}", tracker.addr);
+        swarm.dial(tracker.addr.clone())?;
+        swarm
+            .behaviour_mut()
+            .autonat
+            .add_server(tracker.peer_id, Some(tracker.addr.clone()));
+    }
 
+    let config = config.clone();
     wasm_bindgen_futures::spawn_local(async move {
-        EventLoop::new(namespace, swarm, command_recv).run().await;
+        EventLoop::new(namespace, config, swarm, command_recv)
+            .run()
+            .await;
     });
 
     Ok(P2PClient(command_send))
@@ -95,36 +129,142 @@ pub fn new_p2p_client(stream_namespace: String) -> Result<P2PClient, JsError> {
 #[wasm_bindgen]
 pub struct P2PClient(mpsc::Sender<Command>);
 
+// The methods take `&self` and return promises instead of being `async fn`: wasm-bindgen keeps
+// the borrow of an async method for as long as its future is pending, so e.g. cancelling a
+// request while it is awaited would fail with "recursive use of an object detected".
 #[wasm_bindgen]
 impl P2PClient {
-    /// Advertise the local node as the provider of the given file on the DHT.
-    pub async fn send_segment(
-        &mut self,
-        segment_id: String,
-        segment: Uint8Array,
-    ) -> Result<(), JsError> {
+    /// Store the given segment and announce it on the stream topic, so that other peers can
+    /// request it.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn send_segment(&self, segment_id: String, segment: Uint8Array) -> Promise {
         let data = segment.to_vec();
-        self.0
-            .send(Command::ProvideSegment { segment_id, data })
-            .await?;
-        Ok(())
+        let mut commands = self.0.clone();
+        spawn(async move {
+            commands
+                .send(Command::ProvideSegment { segment_id, data })
+                .await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Request the content of the given segment from the peers holding it. Fails if the
+    /// segment is not received within `timeout_ms` milliseconds (5 seconds by default).
+    /// Concurrent requests for the same segment share its download. Pass an id from
+    /// `new_request_id` to be able to cancel the request with `cancel_request`.
+    #[wasm_bindgen(unchecked_return_type = "Promise<Uint8Array>")]
+    pub fn request_segment(
+        &self,
+        segment_id: String,
+        timeout_ms: Option<u32>,
+        request_id: Option<u32>,
+    ) -> Promise {
+        let timeout = timeout_ms
+            .map(|ms| Duration::from_millis(ms.into()))
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT);
+        let request_id = request_id.unwrap_or_else(|| self.new_request_id());
+        let mut commands = self.0.clone();
+        spawn(async move {
+            let (sender, receiver) = oneshot::channel();
+            commands
+                .send(Command::RequestSegment {
+                    request_id,
+                    segment_id,
+                    deadline: Instant::now() + timeout,
+                    sender,
+                })
+                .await?;
+
+            let segment = receiver.await??;
+            Ok(Uint8Array::from(segment.as_slice()).into())
+        })
+    }
+
+    /// A new identifier for `request_segment`, unique across the clients of the page.
+    pub fn new_request_id(&self) -> u32 {
+        NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Set the expected SHA-256 digest (hex encoded) of a segment, e.g. from the hash list
+    /// published by the origin. Segments received from peers that do not match it are
+    /// discarded and requested again from other peers. Ignored when a publisher key is set,
+    /// use `add_manifest` instead.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn add_segment_digest(&self, segment_id: String, sha256: String) -> Promise {
+        let mut commands = self.0.clone();
+        spawn(async move {
+            let digest = parse_digest(&sha256).ok_or_else(|| {
+                JsError::new(&format!("Invalid SHA-256 digest for segment {segment_id}"))
+            })?;
+            commands
+                .send(Command::AddSegmentDigest { segment_id, digest })
+                .await?;
+            Ok(JsValue::UNDEFINED)
+        })
     }
 
-    /// Request the content of the given file from the given peer.
-    pub async fn request_segment(&mut self, segment_id: String) -> Result<Uint8Array, JsError> {
-        let (sender, receiver) = oneshot::channel();
-        self.0
-            .send(Command::RequestSegment { segment_id, sender })
-            .await?;
+    /// Add the entries of a segment manifest signed by the publisher, see
+    /// `P2PConfig.set_publisher_key`. The manifest lists one `<segment id> <hex sha256>` per
+    /// line and `signature` is the hex encoded ed25519 signature of the whole text. Resolves
+    /// to the number of segments listed.
+    #[wasm_bindgen(unchecked_return_type = "Promise<number>")]
+    pub fn add_manifest(&self, manifest: String, signature: String) -> Promise {
+        let mut commands = self.0.clone();
+        spawn(async move {
+            let signature = hex::decode(signature.trim())
+                .map_err(|e| JsError::new(&format!("Invalid manifest signature: {e}")))?;
+            let (sender, receiver) = oneshot::channel();
+            commands
+                .send(Command::AddManifest {
+                    manifest,
+                    signature,
+                    sender,
+                })
+                .await?;
+            let count = receiver.await??;
+            Ok(JsValue::from(count as u32))
+        })
+    }
 
-        let segment = receiver.await??;
-        let buf = Uint8Array::from(segment.as_slice());
+    /// Cancel a request made with `request_segment`, rejecting its promise. Other requests for
+    /// the same segment keep waiting, its download stops once none is left.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn cancel_request(&self, request_id: u32) -> Promise {
+        let mut commands = self.0.clone();
+        spawn(async move {
+            commands.send(Command::CancelRequest { request_id }).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
 
-        Ok(buf)
+    /// Current connection, storage and NAT traversal counters of the client.
+    #[wasm_bindgen(unchecked_return_type = "Promise<ClientStats>")]
+    pub fn stats(&self) -> Promise {
+        let mut commands = self.0.clone();
+        spawn(async move {
+            let (sender, receiver) = oneshot::channel();
+            commands.send(Command::Stats { sender }).await?;
+            Ok(receiver.await?.into())
+        })
     }
 
-    pub async fn quit(&mut self) -> Result<(), JsError> {
-        self.0.send(Command::Quit).await?;
-        Ok(())
+    /// Leave the swarm and stop the client. Resolves once the teardown is complete.
+    #[wasm_bindgen(unchecked_return_type = "Promise<void>")]
+    pub fn quit(&self) -> Promise {
+        let mut commands = self.0.clone();
+        spawn(async move {
+            let (sender, receiver) = oneshot::channel();
+            if commands.send(Command::Quit { sender }).await.is_err() {
+                // The event loop is already gone.
+                return Ok(JsValue::UNDEFINED);
+            }
+            let _ = receiver.await;
+            Ok(JsValue::UNDEFINED)
+        })
     }
 }
+
+/// Turn a future into a promise that does not borrow the client.
+fn spawn(future: impl Future<Output = Result<JsValue, JsError>> + 'static) -> Promise {
+    future_to_promise(async move { future.await.map_err(JsValue::from) })
+}
@@ -0,0 +1,213 @@
+use libp2p::{
+    PeerId,
+    identity::ed25519,
+    multiaddr::{Multiaddr, Protocol},
+};
+use std::time::Duration;
+use wasm_bindgen::prelude::*;
+
+use super::selection::PeerSelection;
+
+const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_DISCOVERY_LIMIT: u64 = 20;
+const DEFAULT_REGISTRATION_TTL: u64 = 60;
+// Browsers keep few connections, aim for a small gossip mesh.
+const DEFAULT_MESH_N_LOW: usize = 3;
+const DEFAULT_MESH_N: usize = 4;
+const DEFAULT_MESH_N_HIGH: usize = 8;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Segment data goes over the segment protocol, gossip only carries announcements.
+const DEFAULT_MAX_TRANSMIT_SIZE: usize = 64 * 1024;
+
+/// A tracker (rendezvous server) the client dials, registers with and discovers peers from.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    pub peer_id: PeerId,
+    /// Full address of the tracker, including its `/p2p/<peer id>` suffix.
+    pub addr: Multiaddr,
+}
+
+/// Options of a P2P client, built from JS before calling `new_p2p_client`.
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct P2PConfig {
+    trackers: Vec<Tracker>,
+    discovery_interval: Duration,
+    discovery_limit: u64,
+    registration_ttl: u64,
+    publisher_key: Option<ed25519::PublicKey>,
+    peer_selection: PeerSelection,
+    mesh_n_low: usize,
+    mesh_n: usize,
+    mesh_n_high: usize,
+    heartbeat_interval: Duration,
+    max_transmit_size: usize,
+}
+
+impl Default for P2PConfig {
+    fn default() -> Self {
+        Self {
+            trackers: Vec::new(),
+            discovery_interval: DEFAULT_DISCOVERY_INTERVAL,
+            discovery_limit: DEFAULT_DISCOVERY_LIMIT,
+            registration_ttl: DEFAULT_REGISTRATION_TTL,
+            publisher_key: None,
+            peer_selection: PeerSelection::default(),
+            mesh_n_low: DEFAULT_MESH_N_LOW,
+            mesh_n: DEFAULT_MESH_N,
+            mesh_n_high: DEFAULT_MESH_N_HIGH,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            max_transmit_size: DEFAULT_MAX_TRANSMIT_SIZE,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl P2PConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a tracker by its multiaddr, which must end with the tracker peer ID, e.g.
+    /// `/dns/rendezvous.marecchia.io/tcp/443/wss/p2p/12D3KooW...`.
+    pub fn add_tracker(&mut self, addr: &str) -> Result<(), JsError> {
+        let addr: Multiaddr = addr.parse()?;
+        let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
+            return Err(JsError::new(&format!(
+                "Tracker address {addr} does not end with /p2p/<peer id>"
+            )));
+        };
+        if self.trackers.iter().any(|t| t.peer_id == peer_id) {
+            return Err(JsError::new(&format!("Tracker {peer_id} added twice")));
+        }
+
+        self.trackers.push(Tracker { peer_id, addr });
+        Ok(())
+    }
+
+    /// Seconds between two discoveries of new peers from the trackers.
+    pub fn set_discovery_interval(&mut self, secs: u32) -> Result<(), JsError> {
+        if secs == 0 {
+            return Err(JsError::new(
+                "Discovery interval must be at least one second",
+            ));
+        }
+        self.discovery_interval = Duration::from_secs(secs.into());
+        Ok(())
+    }
+
+    /// Maximum number of peers returned by a single discovery.
+    pub fn set_discovery_limit(&mut self, limit: u32) -> Result<(), JsError> {
+        if limit == 0 {
+            return Err(JsError::new("Discovery limit must be at least 1"));
+        }
+        self.discovery_limit = limit.into();
+        Ok(())
+    }
+
+    /// Seconds the trackers keep our registration. It is renewed ahead of expiry, and must
+    /// be within the TTL bounds accepted by the trackers.
+    pub fn set_registration_ttl(&mut self, secs: u32) -> Result<(), JsError> {
+        if secs < 10 {
+            return Err(JsError::new("Registration TTL must be at least 10 seconds"));
+        }
+        self.registration_ttl = secs.into();
+        Ok(())
+    }
+
+    /// Hex encoded ed25519 public key of the broadcaster. Once set, only segments listed in a
+    /// manifest signed with this key are downloaded from or served to other peers.
+    pub fn set_publisher_key(&mut self, key: &str) -> Result<(), JsError> {
+        let bytes = hex::decode(key.trim())
+            .map_err(|e| JsError::new(&format!("Invalid publisher key: {e}")))?;
+        let key = ed25519::PublicKey::try_from_bytes(&bytes)
+            .map_err(|e| JsError::new(&format!("Invalid publisher key: {e}")))?;
+        self.publisher_key = Some(key);
+        Ok(())
+    }
+
+    /// How holders of a segment are picked: `fastest` (default) prefers the peers expected to
+    /// deliver a chunk first, `latency` the lowest round-trip times and `announced` the most
+    /// recent announcers.
+    pub fn set_peer_selection(&mut self, policy: &str) -> Result<(), JsError> {
+        self.peer_selection = PeerSelection::from_name(policy)
+            .ok_or_else(|| JsError::new(&format!("Unknown peer selection policy {policy:?}")))?;
+        Ok(())
+    }
+
+    /// Number of peers in the gossip mesh of the stream topic: `target` is kept between `low`
+    /// and `high`.
+    pub fn set_mesh_size(&mut self, low: u32, target: u32, high: u32) -> Result<(), JsError> {
+        if !(1 <= low && low <= target && target <= high) {
+            return Err(JsError::new(
+                "Mesh size must satisfy 1 <= low <= target <= high",
+            ));
+        }
+        self.mesh_n_low = low as usize;
+        self.mesh_n = target as usize;
+        self.mesh_n_high = high as usize;
+        Ok(())
+    }
+
+    /// Milliseconds between two gossipsub heartbeats, which maintain the mesh.
+    pub fn set_heartbeat_interval(&mut self, ms: u32) -> Result<(), JsError> {
+        if ms < 100 {
+            return Err(JsError::new(
+                "Heartbeat interval must be at least 100 milliseconds",
+            ));
+        }
+        self.heartbeat_interval = Duration::from_millis(ms.into());
+        Ok(())
+    }
+
+    /// Maximum size in bytes of a gossip message, announcements included.
+    pub fn set_max_transmit_size(&mut self, bytes: u32) -> Result<(), JsError> {
+        if bytes < 1024 {
+            return Err(JsError::new(
+                "Max transmit size must be at least 1024 bytes",
+            ));
+        }
+        self.max_transmit_size = bytes as usize;
+        Ok(())
+    }
+}
+
+impl P2PConfig {
+    pub fn trackers(&self) -> &[Tracker] {
+        &self.trackers
+    }
+
+    pub fn discovery_interval(&self) -> Duration {
+        self.discovery_interval
+    }
+
+    pub fn discovery_limit(&self) -> u64 {
+        self.discovery_limit
+    }
+
+    pub fn registration_ttl(&self) -> u64 {
+        self.registration_ttl
+    }
+
+    pub fn publisher_key(&self) -> Option<&ed25519::PublicKey> {
+        self.publisher_key.as_ref()
+    }
+
+    pub fn peer_selection(&self) -> PeerSelection {
+        self.peer_selection
+    }
+
+    /// Mesh size bounds as `(low, target, high)`.
+    pub fn mesh_size(&self) -> (usize, usize, usize) {
+        (self.mesh_n_low, self.mesh_n, self.mesh_n_high)
+    }
+
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.heartbeat_interval
+    }
+
+    pub fn max_transmit_size(&self) -> usize {
+        self.max_transmit_size
+    }
+}
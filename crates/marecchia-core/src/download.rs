@@ -0,0 +1,342 @@
+use libp2p::{PeerId, request_response::OutboundRequestId};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::Duration,
+};
+use web_time::Instant;
+
+use super::chunk::{Chunk, ChunkAssembler, ChunkError};
+
+/// Maximum number of peers fetching chunks of the same segment at once.
+pub const MAX_PARALLEL_PEERS: usize = 4;
+/// A chunk requested longer ago than this is requested again from another idle peer.
+pub const SLOW_CHUNK_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct InFlightChunk {
+    peer: PeerId,
+    chunk: u32,
+    started: Instant,
+}
+
+impl InFlightChunk {
+    fn is_slow(&self, now: Instant) -> bool {
+        now.duration_since(self.started) > SLOW_CHUNK_TIMEOUT
+    }
+}
+
+/// Download of a single segment, spreading its chunks over the peers holding it.
+///
+/// Each holder has at most one chunk request in flight. Until the first chunk tells how many
+/// chunks the segment has, only chunk 0 is requested, from a second holder if the first one is
+/// slow.
+pub struct SegmentDownload<R = OutboundRequestId> {
+    holders: Vec<PeerId>,
+    /// Chunk requests by id, generic so that scheduling can be tested without a swarm.
+    in_flight: HashMap<R, InFlightChunk>,
+    chunks: ChunkAssembler,
+    /// Peers that sent the chunks received so far.
+    contributors: HashSet<PeerId>,
+    /// Peers that contributed to a copy of the segment failing verification.
+    excluded: HashSet<PeerId>,
+}
+
+impl<R: Hash + Eq> SegmentDownload<R> {
+    pub fn new(holders: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            holders: holders.into_iter().collect(),
+            in_flight: HashMap::new(),
+            chunks: ChunkAssembler::new(),
+            contributors: HashSet::new(),
+            excluded: HashSet::new(),
+        }
+    }
+
+    /// Add a peer holding the segment. Returns `false` if it was already known or excluded.
+    pub fn add_holder(&mut self, peer: PeerId) -> bool {
+        if self.holders.contains(&peer) || self.excluded.contains(&peer) {
+            return false;
+        }
+        // Most recent announcers first, unless the peer selector reorders them.
+        self.holders.insert(0, peer);
+        true
+    }
+
+    /// Reorder the holders, the first idle ones are asked for chunks first.
+    pub fn rank_holders(&mut self, rank: impl FnOnce(&mut [PeerId])) {
+        rank(&mut self.holders);
+    }
+
+    /// Stop asking `peer`, e.g. because it does not have the segment or sent garbage.
+    pub fn remove_holder(&mut self, peer: &PeerId) {
+        self.holders.retain(|p| p != peer);
+    }
+
+    /// Pick which chunk to request from which idle holder.
+    ///
+    /// Chunks nobody was asked for come first, then chunks whose only request is slower than
+    /// [`SLOW_CHUNK_TIMEOUT`], which are raced against another peer.
+    pub fn schedule(&self, now: Instant) -> Vec<(PeerId, u32)> {
+        let busy: HashSet<PeerId> = self.in_flight.values().map(|f| f.peer).collect();
+        let mut idle = self
+            .holders
+            .iter()
+            .filter(|peer| !busy.contains(peer))
+            .take(MAX_PARALLEL_PEERS.saturating_sub(busy.len()));
+
+        if self.chunks.total().is_none() {
+            // Only chunk 0 can be in flight, race it like any other slow chunk.
+            let request = match self.in_flight.values().collect::<Vec<_>>().as_slice() {
+                [] => true,
+                [only] => only.is_slow(now),
+                _ => false,
+            };
+            return match (request, idle.next()) {
+                (true, Some(peer)) => vec![(*peer, 0)],
+                _ => Vec::new(),
+            };
+        }
+
+        let mut requests: HashMap<u32, Vec<&InFlightChunk>> = HashMap::new();
+        for in_flight in self.in_flight.values() {
+            requests.entry(in_flight.chunk).or_default().push(in_flight);
+        }
+        let unrequested = self
+            .chunks
+            .missing()
+            .filter(|chunk| !requests.contains_key(chunk));
+        let slow = self.chunks.missing().filter(|chunk| {
+            matches!(requests.get(chunk).map(Vec::as_slice), Some([only]) if only.is_slow(now))
+        });
+
+        idle.zip(unrequested.chain(slow))
+            .map(|(peer, chunk)| (*peer, chunk))
+            .collect()
+    }
+
+    pub fn started(&mut self, request_id: R, peer: PeerId, chunk: u32) {
+        self.in_flight.insert(
+            request_id,
+            InFlightChunk {
+                peer,
+                chunk,
+                started: Instant::now(),
+            },
+        );
+    }
+
+    /// Forget a chunk request once it got a response or failed. Returns how long it took.
+    pub fn finished(&mut self, request_id: &R) -> Option<Duration> {
+        self.in_flight
+            .remove(request_id)
+            .map(|in_flight| in_flight.started.elapsed())
+    }
+
+    pub fn insert(&mut self, peer: PeerId, chunk: Chunk) -> Result<(), ChunkError> {
+        self.chunks.insert(chunk)?;
+        self.contributors.insert(peer);
+        Ok(())
+    }
+
+    /// Whether `chunk` from `peer` disagrees with the layout of the chunks other peers sent,
+    /// e.g. because one side lies or plays another rendition under the same segment id.
+    pub fn conflicts(&self, peer: &PeerId, chunk: &Chunk) -> bool {
+        self.chunks.conflicts(chunk) && !self.contributors.contains(peer)
+    }
+
+    /// Which side of a conflict is wrong cannot be told before the segment is verified: start
+    /// over from the chunk of `peer` and stop asking the peers that sent the previous ones,
+    /// without blaming them. Returns the excluded peers.
+    pub fn restart_from(&mut self, peer: PeerId, chunk: Chunk) -> Result<Vec<PeerId>, ChunkError> {
+        let mut chunks = ChunkAssembler::new();
+        chunks.insert(chunk)?;
+        let excluded = self.reject_contributors();
+        self.chunks = chunks;
+        self.contributors.insert(peer);
+        Ok(excluded)
+    }
+
+    pub fn received(&self) -> u32 {
+        self.chunks.received()
+    }
+
+    pub fn total(&self) -> Option<u32> {
+        self.chunks.total()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.chunks.is_complete()
+    }
+
+    /// No holder left to ask and nothing in flight: the download cannot make progress.
+    pub fn is_stalled(&self) -> bool {
+        self.holders.is_empty() && self.in_flight.is_empty()
+    }
+
+    pub fn is_excluded(&self, peer: &PeerId) -> bool {
+        self.excluded.contains(peer)
+    }
+
+    /// Assemble the received chunks, leaving the download empty.
+    pub fn take_segment(&mut self) -> Result<Vec<u8>, ChunkError> {
+        std::mem::take(&mut self.chunks).assemble()
+    }
+
+    /// The assembled segment failed verification: stop asking the peers that sent its chunks
+    /// and start over with the others. Returns the excluded peers.
+    pub fn reject_contributors(&mut self) -> Vec<PeerId> {
+        let rejected: Vec<PeerId> = self.contributors.drain().collect();
+        self.holders.retain(|peer| !rejected.contains(peer));
+        self.in_flight
+            .retain(|_, in_flight| !rejected.contains(&in_flight.peer));
+        self.excluded.extend(rejected.iter().copied());
+        rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::CHUNK_SIZE;
+
+    fn chunk(segment: &[u8], index: u32) -> Chunk {
+        Chunk::from_segment(segment, index).unwrap()
+    }
+
+    #[test]
+    fn restarts_from_a_conflicting_chunk() {
+        let (first, second, third) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut download = SegmentDownload::<u32>::new([first, second, third]);
+        let honest = vec![1; CHUNK_SIZE + 10];
+        let other = vec![2; CHUNK_SIZE + 20];
+
+        download.insert(first, chunk(&other, 0)).unwrap();
+        // A peer contradicting itself is not a conflict, its chunk is just wrong.
+        assert!(!download.conflicts(&first, &chunk(&honest, 1)));
+        assert!(download.conflicts(&second, &chunk(&honest, 1)));
+
+        assert_eq!(
+            download.restart_from(second, chunk(&honest, 1)),
+            Ok(vec![first])
+        );
+        assert!(download.is_excluded(&first));
+        assert_eq!(download.received(), 1);
+        download.insert(third, chunk(&honest, 0)).unwrap();
+        assert!(download.is_complete());
+        assert_eq!(download.take_segment().unwrap(), honest);
+    }
+
+    #[test]
+    fn keeps_the_layout_when_the_conflicting_chunk_is_malformed() {
+        let (first, second) = (PeerId::random(), PeerId::random());
+        let mut download = SegmentDownload::<u32>::new([first, second]);
+        let segment = vec![1; CHUNK_SIZE + 10];
+        download.insert(first, chunk(&segment, 0)).unwrap();
+
+        let malformed = Chunk {
+            total: 3,
+            ..chunk(&segment, 1)
+        };
+        assert!(download.conflicts(&second, &malformed));
+        assert_eq!(
+            download.restart_from(second, malformed),
+            Err(ChunkError::Inconsistent)
+        );
+        assert!(!download.is_excluded(&first));
+        assert_eq!(download.received(), 1);
+    }
+
+    /// Holders asked for each chunk by `schedule`, recorded as started.
+    fn start(
+        download: &mut SegmentDownload<u32>,
+        next_id: &mut u32,
+        now: Instant,
+    ) -> Vec<(PeerId, u32)> {
+        let scheduled = download.schedule(now);
+        for (peer, chunk) in &scheduled {
+            download.started(*next_id, *peer, *chunk);
+            *next_id += 1;
+        }
+        scheduled
+    }
+
+    #[test]
+    fn asks_one_holder_for_the_first_chunk() {
+        let holders: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let mut download = SegmentDownload::new(holders.clone());
+        let mut next_id = 0;
+        let now = Instant::now();
+
+        assert_eq!(start(&mut download, &mut next_id, now), [(holders[0], 0)]);
+        // Nothing else to ask until the first chunk tells how many there are.
+        assert!(start(&mut download, &mut next_id, now).is_empty());
+    }
+
+    #[test]
+    fn races_a_slow_first_chunk_once() {
+        let holders: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let mut download = SegmentDownload::new(holders.clone());
+        let mut next_id = 0;
+        start(&mut download, &mut next_id, Instant::now());
+
+        let late = Instant::now() + SLOW_CHUNK_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(start(&mut download, &mut next_id, late), [(holders[1], 0)]);
+        assert!(start(&mut download, &mut next_id, late).is_empty());
+    }
+
+    #[test]
+    fn spreads_chunks_over_idle_holders() {
+        let holders: Vec<PeerId> = (0..MAX_PARALLEL_PEERS + 2)
+            .map(|_| PeerId::random())
+            .collect();
+        let mut download = SegmentDownload::new(holders.clone());
+        let mut next_id = 0;
+        let now = Instant::now();
+        let segment = vec![0; 8 * CHUNK_SIZE];
+        start(&mut download, &mut next_id, now);
+        assert!(download.finished(&0).is_some());
+        download.insert(holders[0], chunk(&segment, 0)).unwrap();
+
+        // Every other chunk goes to its own holder, up to the parallelism limit.
+        let scheduled = start(&mut download, &mut next_id, now);
+        let expected: Vec<(PeerId, u32)> = holders
+            .iter()
+            .copied()
+            .zip(1..)
+            .take(MAX_PARALLEL_PEERS)
+            .collect();
+        assert_eq!(scheduled, expected);
+        assert!(start(&mut download, &mut next_id, now).is_empty());
+
+        // A holder done with its chunk gets the next one.
+        let (peer, index) = scheduled[1];
+        assert!(download.finished(&2).is_some());
+        download.insert(peer, chunk(&segment, index)).unwrap();
+        assert_eq!(
+            start(&mut download, &mut next_id, now),
+            [(peer, MAX_PARALLEL_PEERS as u32 + 1)]
+        );
+    }
+
+    #[test]
+    fn races_slow_chunks_against_idle_holders() {
+        let holders: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        let mut download = SegmentDownload::new(holders.clone());
+        let mut next_id = 0;
+        let segment = vec![0; 2 * CHUNK_SIZE];
+        start(&mut download, &mut next_id, Instant::now());
+        download.finished(&0);
+        download.insert(holders[0], chunk(&segment, 0)).unwrap();
+        assert_eq!(
+            start(&mut download, &mut next_id, Instant::now()),
+            [(holders[0], 1)]
+        );
+
+        let late = Instant::now() + SLOW_CHUNK_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(start(&mut download, &mut next_id, late), [(holders[1], 1)]);
+        // A chunk is raced against one other holder only.
+        assert!(start(&mut download, &mut next_id, late).is_empty());
+        download.insert(holders[1], chunk(&segment, 1)).unwrap();
+        assert!(download.is_complete());
+    }
+}
@@ -1,57 +1,129 @@
+use futures_timer::Delay;
 use libp2p::core::Multiaddr;
+use libp2p::core::transport::ListenerId;
 use libp2p::futures::{
     channel::{mpsc, oneshot},
     prelude::*,
 };
-use libp2p::gossipsub::{self, IdentTopic, SubscriptionError};
+use libp2p::gossipsub::{self, IdentTopic, MessageAcceptance};
 use libp2p::multiaddr::Protocol;
-use libp2p::rendezvous::{Cookie, Namespace, client as rendezvous};
+use libp2p::rendezvous::{Cookie, ErrorCode, Namespace, client as rendezvous};
+use libp2p::request_response::{self, OutboundRequestId};
 use libp2p::swarm::{Swarm, SwarmEvent};
-use libp2p::{PeerId, identify, ping, relay};
+use libp2p::{PeerId, autonat, dcutr, identify, ping, relay};
 use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 use wasm_bindgen::JsError;
+use web_time::Instant;
 
+use super::availability::{Availability, Holdings};
 use super::behaviour::*;
+use super::chunk::{Chunk, ChunkError};
+use super::config::P2PConfig;
+use super::download::SegmentDownload;
+use super::integrity::{
+    DEFAULT_DIGEST_CAPACITY, ManifestError, SegmentDigest, SegmentDigests, Verification,
+    verify_manifest,
+};
+use super::protocol::{SegmentRequest, SegmentResponse};
+use super::reputation::{Offence, Reputation};
+use super::selection::{PeerSelector, rank_peers};
+use super::stats::ClientStats;
+use super::store::{DEFAULT_STORE_CAPACITY, SegmentStore};
 
 pub struct EventLoop {
     namespace: Namespace,
-    cookie: Cookie,
+    /// Topic of the stream, where its peers announce the segments they hold.
+    topic: IdentTopic,
+    availability: Availability,
+    /// When to announce the segments we hold, `None` until something is stored.
+    announce_at: Option<Instant>,
+    config: P2PConfig,
+    trackers: HashMap<PeerId, TrackerState>,
+    next_discovery: Instant,
+    last_discovery: Option<Instant>,
     swarm: Swarm<ComposedSwarmBehaviour>,
     command_receiver: mpsc::Receiver<Command>,
     segment_request: SegmentRequestCache,
+    store: SegmentStore,
+    digests: SegmentDigests,
+    reputation: Reputation,
+    selector: Box<dyn PeerSelector>,
+    downloads: HashMap<String, SegmentDownload>,
+    outbound: HashMap<OutboundRequestId, String>,
+    housekeeping: Delay,
+    stats: ClientStats,
+}
+
+/// Rendezvous bookkeeping of a configured tracker.
+#[derive(Default)]
+struct TrackerState {
+    /// Last discovery cookie, so that only new registrations are returned.
+    cookie: Option<Cookie>,
+    /// Whether the tracker currently holds our registration.
+    registered: bool,
+    /// When to (re-)register, `None` while a registration is pending or not connected.
+    register_at: Option<Instant>,
+    /// When to dial the tracker again after losing the connection.
+    redial_at: Option<Instant>,
+    /// Listener of the circuit reserved on the tracker relay, peers reach us through it.
+    circuit: Option<ListenerId>,
+    /// When to ask the tracker relay for a reservation again after losing or failing one.
+    listen_at: Option<Instant>,
 }
 
+/// How often pending downloads are checked for slow chunks and timers are checked.
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_millis(500);
+/// How often the segments we hold are announced again, for peers that joined since.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(10);
+/// Minimum time between discoveries triggered by peers leaving.
+const MIN_DISCOVERY_GAP: Duration = Duration::from_secs(5);
+/// Delay before retrying a failed registration or dialing a lost tracker again.
+const TRACKER_RETRY_DELAY: Duration = Duration::from_secs(10);
+/// Time given to unregister and unsubscribe messages to go out before disconnecting.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+/// Maximum time spent waiting for connections to close on shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
 impl EventLoop {
     pub fn new(
         namespace: Namespace,
+        config: P2PConfig,
         swarm: Swarm<ComposedSwarmBehaviour>,
         command_receiver: mpsc::Receiver<Command>,
     ) -> Self {
         Self {
-            cookie: Cookie::for_namespace(namespace.clone()),
+            topic: IdentTopic::new(namespace.to_string()),
+            availability: Availability::new(),
+            announce_at: None,
             namespace,
+            next_discovery: Instant::now() + config.discovery_interval(),
+            last_discovery: None,
+            trackers: config
+                .trackers()
+                .iter()
+                .map(|t| (t.peer_id, TrackerState::default()))
+                .collect(),
+            selector: config.peer_selection().selector(),
+            config,
             swarm,
             command_receiver,
             segment_request: SegmentRequestCache::new(10),
+            store: SegmentStore::new(DEFAULT_STORE_CAPACITY),
+            digests: SegmentDigests::new(DEFAULT_DIGEST_CAPACITY),
+            reputation: Reputation::new(),
+            downloads: HashMap::new(),
+            outbound: HashMap::new(),
+            housekeeping: Delay::new(HOUSEKEEPING_INTERVAL),
+            stats: ClientStats::default(),
         }
     }
 
     pub async fn run(mut self) {
-        // Register with the rendezvous node.
-        // TODO: move the rendevouz connection in the client.rs file
-        let rendezvous_node = PeerId::random();
-        match self.swarm.behaviour_mut().rendezvous.register(
-            self.namespace.clone(),
-            rendezvous_node,
-            Some(60),
-        ) {
-            Ok(_) => {}
-            Err(e) => {
-                tracing::error!("Failed to register with rendezvous node: {:?}", e);
-                return;
-            }
+        // Registration and discovery start once the trackers dialed by the client connect.
+        if let Err(e) = self.swarm.behaviour_mut().pubsub.subscribe(&self.topic) {
+            tracing::error!("Failed to subscribe to segment announcements: {:?}", e);
         }
-
         loop {
             libp2p::futures::select! {
                 event = self.swarm.next() => match event {
@@ -59,20 +131,85 @@ impl EventLoop {
                     None => return,
                 },
                 command = self.command_receiver.next() => match command {
+                    Some(Command::Quit { sender }) => {
+                        self.shutdown().await;
+                        let _ = sender.send(());
+                        return;
+                    }
                     Some(c) => self.handle_command(c).await,
                     // Command channel closed, thus shutting down the network event loop.
-                    None=>  return,
+                    None => {
+                        self.shutdown().await;
+                        return;
+                    }
+                },
+                _ = (&mut self.housekeeping).fuse() => {
+                    self.housekeeping.reset(HOUSEKEEPING_INTERVAL);
+                    self.handle_housekeeping();
+                },
+            }
+        }
+    }
+
+    /// Leave the swarm cleanly: fail pending requests, unsubscribe from every topic,
+    /// unregister from the trackers and close all connections.
+    async fn shutdown(&mut self) {
+        tracing::info!("Shutting down the network event loop");
+        self.segment_request.fail_all(RequestError::Shutdown);
+        self.downloads.clear();
+        self.outbound.clear();
+
+        let topics: Vec<_> = self.swarm.behaviour().pubsub.topics().cloned().collect();
+        for topic in topics {
+            let _ = self
+                .swarm
+                .behaviour_mut()
+                .pubsub
+                .unsubscribe(&IdentTopic::new(topic.into_string()));
+        }
+        self.unregister();
+
+        // Keep polling the swarm for the messages above to be sent.
+        let mut grace = Delay::new(SHUTDOWN_GRACE).fuse();
+        loop {
+            libp2p::futures::select! {
+                _ = self.swarm.select_next_some() => {},
+                _ = grace => break,
+            }
+        }
+
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        let mut timeout = Delay::new(SHUTDOWN_TIMEOUT).fuse();
+        while self.swarm.connected_peers().next().is_some() {
+            libp2p::futures::select! {
+                _ = self.swarm.select_next_some() => {},
+                _ = timeout => {
+                    tracing::warn!("Timed out waiting for connections to close");
+                    break;
                 },
             }
         }
+        tracing::info!("Network event loop shut down");
     }
 
     async fn handle_event(&mut self, event: SwarmEvent<ComposedSwarmEvent>) {
         match event {
             SwarmEvent::Behaviour(behaviour) => self.handle_behaviour_event(behaviour).await,
-            SwarmEvent::NewListenAddr { address, .. } => {
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
                 // Started listening on a new address.
                 tracing::info!("Local node is listening on {:?}", address);
+                if self.is_circuit(listener_id) {
+                    // Browsers cannot be dialed directly, the relayed address is the one to
+                    // hand out to the other peers.
+                    self.swarm.add_external_address(address);
+                    self.register_all();
+                }
             }
             SwarmEvent::IncomingConnection {
                 connection_id,
@@ -87,7 +224,10 @@ impl EventLoop {
                 );
             }
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
                 // A new connection has been established. (Either incoming or outgoing)
                 tracing::info!(
@@ -95,6 +235,15 @@ impl EventLoop {
                     peer_id,
                     endpoint.to_endpoint()
                 );
+                if num_established.get() == 1 && self.is_tracker(&peer_id) {
+                    // (Re)connected to a tracker, (re)register and look for peers.
+                    if let Some(tracker) = self.trackers.get_mut(&peer_id) {
+                        tracker.redial_at = None;
+                    }
+                    self.listen_via(peer_id);
+                    self.register(peer_id);
+                    self.discover(peer_id);
+                }
             }
             SwarmEvent::ConnectionClosed {
                 connection_id,
@@ -112,6 +261,26 @@ impl EventLoop {
                     cause,
                     num_established
                 );
+                if num_established == 0 {
+                    if let Some(tracker) = self.trackers.get_mut(&peer_id) {
+                        // The tracker forgets our registration when it expires, not on
+                        // disconnection, but the renewal needs a connection anyway.
+                        tracing::warn!("Lost connection to rendezvous node {:?}", peer_id);
+                        tracker.registered = false;
+                        tracker.register_at = None;
+                        tracker.redial_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+                        // The reservation goes with the connection, a new one is made once
+                        // reconnected.
+                        tracker.listen_at = None;
+                        return;
+                    }
+                    self.availability.remove_peer(&peer_id);
+                    // Look for replacements sooner than the next periodic discovery.
+                    let soonest = self
+                        .last_discovery
+                        .map_or_else(Instant::now, |last| last + MIN_DISCOVERY_GAP);
+                    self.next_discovery = self.next_discovery.min(soonest);
+                }
             }
             SwarmEvent::OutgoingConnectionError {
                 connection_id,
@@ -126,6 +295,12 @@ impl EventLoop {
                     peer_id,
                     error
                 );
+                if let Some(peer_id) = peer_id
+                    && !self.swarm.is_connected(&peer_id)
+                    && let Some(tracker) = self.trackers.get_mut(&peer_id)
+                {
+                    tracker.redial_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+                }
             }
             SwarmEvent::IncomingConnectionError {
                 peer_id,
@@ -152,11 +327,14 @@ impl EventLoop {
                 connection_id, peer_id
             ),
             SwarmEvent::ExpiredListenAddr {
-                listener_id: _listener,
+                listener_id,
                 address,
             } => {
                 // A listening address has expired.
                 tracing::warn!("Listening address {:?} expired", address);
+                if self.is_circuit(listener_id) {
+                    self.swarm.remove_external_address(&address);
+                }
             }
             SwarmEvent::ListenerClosed {
                 listener_id,
@@ -170,6 +348,25 @@ impl EventLoop {
                     addresses,
                     reason
                 );
+                for address in &addresses {
+                    self.swarm.remove_external_address(address);
+                }
+                let relay = self
+                    .trackers
+                    .iter()
+                    .find(|(_, tracker)| tracker.circuit == Some(listener_id))
+                    .map(|(peer, _)| *peer);
+                if let Some(relay) = relay {
+                    // The reservation was denied, could not be renewed or its connection closed.
+                    let connected = self.swarm.is_connected(&relay);
+                    if let Some(tracker) = self.trackers.get_mut(&relay) {
+                        tracker.circuit = None;
+                        if connected {
+                            tracing::warn!("Lost the circuit reserved on relay {:?}", relay);
+                            tracker.listen_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+                        }
+                    }
+                }
             }
             SwarmEvent::ListenerError { listener_id, error } => {
                 // A listener has encountered a non fatal error.
@@ -187,10 +384,13 @@ impl EventLoop {
     async fn handle_behaviour_event(&mut self, event: ComposedSwarmEvent) {
         match event {
             ComposedSwarmEvent::Ping(event) => self.handle_ping_event(event).await,
+            ComposedSwarmEvent::Autonat(event) => self.handle_autonat_event(event).await,
+            ComposedSwarmEvent::Dcutr(event) => self.handle_dcutr_event(event).await,
             ComposedSwarmEvent::Identify(event) => self.handle_identify_event(event).await,
             ComposedSwarmEvent::Rendezvous(event) => self.handle_rendezvous_event(event).await,
             ComposedSwarmEvent::Relay(event) => self.handle_relay_event(event).await,
             ComposedSwarmEvent::Gossipsub(event) => self.handle_gossipsub_event(event).await,
+            ComposedSwarmEvent::Segment(event) => self.handle_segment_event(event).await,
         }
     }
 
@@ -202,7 +402,8 @@ impl EventLoop {
                     "Ping to peer {:?} successful, {:?}",
                     ping_event.peer,
                     duration
-                )
+                );
+                self.reputation.record_rtt(ping_event.peer, duration);
             }
             Err(failure) => {
                 // Disconnect from the peer.
@@ -216,6 +417,74 @@ impl EventLoop {
         }
     }
 
+    async fn handle_autonat_event(&mut self, event: autonat::Event) {
+        match event {
+            autonat::Event::StatusChanged { old, new } => {
+                // Our reachability as seen by the AutoNAT servers changed.
+                tracing::info!("NAT status changed from {:?} to {:?}", old, new);
+            }
+            autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Request {
+                probe_id,
+                peer,
+            }) => {
+                tracing::debug!("Sent AutoNAT probe {:?} to peer {:?}", probe_id, peer);
+            }
+            autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Response {
+                probe_id,
+                peer,
+                address,
+            }) => {
+                // The server could dial us back.
+                tracing::info!(
+                    "AutoNAT probe {:?} to peer {:?} reached us at {:?}",
+                    probe_id,
+                    peer,
+                    address
+                );
+                self.stats.autonat_probes += 1;
+            }
+            autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Error {
+                probe_id,
+                peer,
+                error,
+            }) => {
+                tracing::debug!(
+                    "AutoNAT probe {:?} to peer {:?} failed: {:?}",
+                    probe_id,
+                    peer,
+                    error
+                );
+                self.stats.autonat_probe_failures += 1;
+            }
+            autonat::Event::InboundProbe(event) => {
+                // Another peer asked us to dial it back.
+                tracing::debug!("Inbound AutoNAT probe: {:?}", event);
+            }
+        }
+    }
+
+    async fn handle_dcutr_event(&mut self, event: dcutr::Event) {
+        match event.result {
+            Ok(connection_id) => {
+                // The relayed connection was upgraded to a direct one.
+                tracing::info!(
+                    "Hole punched to peer {:?}, direct connection {:?}",
+                    event.remote_peer_id,
+                    connection_id
+                );
+                self.stats.hole_punches += 1;
+            }
+            Err(error) => {
+                tracing::warn!(
+                    "Hole punching to peer {:?} failed, keeping the relayed connection: {}",
+                    event.remote_peer_id,
+                    error
+                );
+                self.stats.hole_punch_failures += 1;
+            }
+        }
+    }
+
     async fn handle_identify_event(&mut self, identify_event: identify::Event) {
         match identify_event {
             identify::Event::Received {
@@ -284,11 +553,21 @@ impl EventLoop {
                     registrations.len(),
                     rendezvous_node
                 );
-                // Update cookie (next requets avoid dsicovering the same peers again)
-                self.cookie = cookie;
+                // Update cookie (next requests avoid discovering the same peers again)
+                if let Some(tracker) = self.trackers.get_mut(&rendezvous_node) {
+                    tracker.cookie = Some(cookie);
+                }
+                let page_full = registrations.len() as u64 >= self.config.discovery_limit();
+                let local_peer_id = *self.swarm.local_peer_id();
                 for registration in registrations {
+                    let peer = registration.record.peer_id();
+                    if peer == local_peer_id
+                        || self.swarm.is_connected(&peer)
+                        || self.reputation.is_banned(&peer)
+                    {
+                        continue;
+                    }
                     for address in registration.record.addresses() {
-                        let peer = registration.record.peer_id();
                         tracing::info!("Dialing peer {:?} with address {:?}", peer, address);
 
                         let p2p_suffix = Protocol::P2p(peer);
@@ -299,9 +578,15 @@ impl EventLoop {
                                 address.clone()
                             };
 
-                        self.swarm.dial(address_with_p2p).unwrap();
+                        if let Err(e) = self.swarm.dial(address_with_p2p) {
+                            tracing::warn!("Failed to dial peer {:?}: {:?}", peer, e);
+                        }
                     }
                 }
+                // A full page means the tracker may hold more registrations, fetch the next one.
+                if page_full {
+                    self.discover(rendezvous_node);
+                }
             }
             rendezvous::Event::DiscoverFailed {
                 rendezvous_node,
@@ -315,6 +600,12 @@ impl EventLoop {
                     namespace,
                     error
                 );
+                if error == ErrorCode::InvalidCookie
+                    && let Some(tracker) = self.trackers.get_mut(&rendezvous_node)
+                {
+                    // Start over, the next discovery returns every registration.
+                    tracker.cookie = None;
+                }
             }
             rendezvous::Event::Expired { peer } => {
                 // Peer registration with the rendezvous node has expired. ()
@@ -332,6 +623,14 @@ impl EventLoop {
                     namespace,
                     ttl
                 );
+                // Never trust the tracker with a longer ttl than asked for.
+                let ttl = ttl.min(self.config.registration_ttl());
+                if let Some(tracker) = self.trackers.get_mut(&rendezvous_node) {
+                    // Renew well ahead of the expiry.
+                    tracker.registered = true;
+                    tracker.register_at = Some(Instant::now() + Duration::from_secs(ttl / 4 * 3));
+                }
+                self.discover(rendezvous_node);
             }
             rendezvous::Event::RegisterFailed {
                 rendezvous_node,
@@ -345,6 +644,10 @@ impl EventLoop {
                     namespace,
                     error
                 );
+                if let Some(tracker) = self.trackers.get_mut(&rendezvous_node) {
+                    tracker.registered = false;
+                    tracker.register_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+                }
             }
         }
     }
@@ -363,6 +666,11 @@ impl EventLoop {
                     renewal,
                     limit
                 );
+                // The relay client renews the reservation before it expires and closes the
+                // listener when that fails, only the first acceptance needs handling.
+                if let Some(tracker) = self.trackers.get_mut(&relay_peer_id) {
+                    tracker.listen_at = None;
+                }
             }
             relay::client::Event::InboundCircuitEstablished { src_peer_id, limit } => {
                 // An inbound circuit has been established.
@@ -400,13 +708,25 @@ impl EventLoop {
                     propagation_source,
                     message.topic.as_str()
                 );
-                if let Some(sender) = self.segment_request.remove(message.topic.as_str()) {
-                    let _ = sender.send(Ok(message.data));
-                }
+                // Relayed announcements are attributed to their author only.
+                let acceptance = match message.source {
+                    Some(source) if message.topic == self.topic.hash() => {
+                        self.handle_have(source, &message.data)
+                    }
+                    _ => MessageAcceptance::Reject,
+                };
+                self.swarm
+                    .behaviour_mut()
+                    .pubsub
+                    .report_message_validation_result(&message_id, &propagation_source, acceptance);
             }
             gossipsub::Event::Subscribed { peer_id, topic } => {
                 // A remote subscribed to a new topic.
                 tracing::info!("Remote peer {:?} subscribed to topic {:?}", peer_id, topic);
+                if topic == self.topic.hash() && self.announce_at.is_some() {
+                    // Let the newcomer know what we hold without waiting for the next round.
+                    self.announce_at = Some(Instant::now());
+                }
             }
             gossipsub::Event::Unsubscribed { peer_id, topic } => {
                 // A remote unsubscribed from a topic.
@@ -438,6 +758,593 @@ impl EventLoop {
         }
     }
 
+    async fn handle_segment_event(
+        &mut self,
+        event: request_response::Event<SegmentRequest, SegmentResponse>,
+    ) {
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => {
+                    // A remote peer asked us for a chunk of a segment. Stored segments with a
+                    // known digest were verified, the others are only served without manifest.
+                    let servable = !self.is_strict() || self.digests.contains(&request.segment_id);
+                    let response = match self
+                        .store
+                        .get(&request.segment_id)
+                        .filter(|_| servable)
+                        .and_then(|data| Chunk::from_segment(data, request.chunk))
+                    {
+                        Some(chunk) => SegmentResponse::Found(chunk),
+                        None => SegmentResponse::NotFound,
+                    };
+                    tracing::debug!(
+                        "Peer {:?} requested chunk {} of segment {:?}, found: {}",
+                        peer,
+                        request.chunk,
+                        request.segment_id,
+                        matches!(response, SegmentResponse::Found(_))
+                    );
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .segment
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        tracing::warn!("Peer {:?} went away before receiving the chunk", peer);
+                    }
+                }
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    let Some(segment_id) = self.outbound.remove(&request_id) else {
+                        return;
+                    };
+                    let Some(download) = self.downloads.get_mut(&segment_id) else {
+                        return;
+                    };
+                    let elapsed = download.finished(&request_id).unwrap_or_default();
+                    match response {
+                        SegmentResponse::Found(chunk) => {
+                            self.handle_chunk(segment_id, peer, chunk, elapsed);
+                        }
+                        SegmentResponse::NotFound => {
+                            tracing::debug!(
+                                "Peer {:?} does not have segment {:?}",
+                                peer,
+                                segment_id
+                            );
+                            download.remove_holder(&peer);
+                            self.schedule_download(&segment_id);
+                        }
+                    }
+                }
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                tracing::warn!("Segment request to peer {:?} failed: {}", peer, error);
+                let Some(segment_id) = self.outbound.remove(&request_id) else {
+                    return;
+                };
+                if matches!(error, request_response::OutboundFailure::Timeout) {
+                    self.penalize(peer, Offence::Timeout);
+                }
+                if let Some(download) = self.downloads.get_mut(&segment_id) {
+                    download.finished(&request_id);
+                    download.remove_holder(&peer);
+                    self.schedule_download(&segment_id);
+                }
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                tracing::warn!("Failed to serve segment request from {:?}: {}", peer, error);
+            }
+            request_response::Event::ResponseSent { peer, .. } => {
+                tracing::debug!("Sent segment response to peer {:?}", peer);
+            }
+        }
+    }
+
+    fn is_tracker(&self, peer: &PeerId) -> bool {
+        self.trackers.contains_key(peer)
+    }
+
+    /// Lower the reputation of `peer`, banning it for the rest of the session once it drops
+    /// to [`BAN_SCORE`](super::reputation::BAN_SCORE).
+    fn penalize(&mut self, peer: PeerId, offence: Offence) {
+        let score = self.reputation.penalize(peer, offence);
+        tracing::debug!(
+            "Penalized peer {:?} for {:?}, score {}",
+            peer,
+            offence,
+            score
+        );
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.pubsub.set_application_score(&peer, score);
+        if !self.reputation.is_banned(&peer) || !behaviour.blocked.block_peer(peer) {
+            return;
+        }
+
+        let (successes, mismatches, timeouts) = self.reputation.counters(&peer);
+        tracing::warn!(
+            "Banning peer {:?}: {} successful transfers ({:.0} B/s), {} hash mismatches, {} timeouts",
+            peer,
+            successes,
+            self.reputation.throughput(&peer).unwrap_or_default(),
+            mismatches,
+            timeouts
+        );
+        // Blocking closes the connections, make sure no download keeps waiting on it.
+        behaviour.pubsub.blacklist_peer(&peer);
+        for download in self.downloads.values_mut() {
+            download.remove_holder(&peer);
+        }
+    }
+
+    /// With a publisher key, only segments listed in a signed manifest are exchanged.
+    fn is_strict(&self) -> bool {
+        self.config.publisher_key().is_some()
+    }
+
+    fn expect_digest(&mut self, segment_id: String, digest: SegmentDigest) {
+        self.digests.insert(segment_id.clone(), digest);
+        // Stop serving a copy that turns out to be corrupt.
+        if let Some(data) = self.store.get(&segment_id)
+            && self.digests.verify(&segment_id, data) == Verification::Mismatch
+        {
+            tracing::warn!(
+                "Stored segment {:?} does not match its digest, dropping it",
+                segment_id
+            );
+            self.store.remove(&segment_id);
+        }
+    }
+
+    fn store_segment(&mut self, segment_id: String, data: Vec<u8>) {
+        let size = data.len();
+        if self.store.insert(segment_id.clone(), data) {
+            self.announce_at = Some(Instant::now());
+            tracing::debug!(
+                "Stored segment {:?}, {} segments ({} bytes) in store",
+                segment_id,
+                self.store.len(),
+                self.store.size()
+            );
+        } else {
+            tracing::warn!(
+                "Segment {:?} of {} bytes does not fit in the store",
+                segment_id,
+                size
+            );
+        }
+    }
+
+    fn handle_chunk(&mut self, segment_id: String, peer: PeerId, chunk: Chunk, elapsed: Duration) {
+        let Some(download) = self.downloads.get_mut(&segment_id) else {
+            return;
+        };
+        if download.is_excluded(&peer) {
+            // Late answer to a request sent before the peer was caught sending bad data.
+            return;
+        }
+        let index = chunk.index;
+        let size = chunk.data.len();
+        let result = if download.conflicts(&peer, &chunk) {
+            download.restart_from(peer, chunk).map(|excluded| {
+                tracing::warn!(
+                    "Chunk {} of segment {:?} from peer {:?} does not match the chunks of peers {:?}, starting over from it",
+                    index,
+                    segment_id,
+                    peer,
+                    excluded
+                );
+            })
+        } else {
+            download.insert(peer, chunk)
+        };
+        if let Err(e) = result {
+            tracing::warn!(
+                "Discarding chunk {} of segment {:?} from peer {:?}: {}",
+                index,
+                segment_id,
+                peer,
+                e
+            );
+            download.remove_holder(&peer);
+            self.penalize(peer, Offence::Malformed);
+            self.schedule_download(&segment_id);
+            return;
+        }
+        let score = self.reputation.record_success(peer, size, elapsed);
+        self.swarm
+            .behaviour_mut()
+            .pubsub
+            .set_application_score(&peer, score);
+        let Some(download) = self.downloads.get_mut(&segment_id) else {
+            return;
+        };
+        tracing::debug!(
+            "Received chunk {} of segment {:?} from peer {:?}, {}/{} chunks",
+            index,
+            segment_id,
+            peer,
+            download.received(),
+            download.total().unwrap_or_default()
+        );
+
+        if !download.is_complete() {
+            self.schedule_download(&segment_id);
+            return;
+        }
+
+        let data = match download.take_segment() {
+            Ok(data) => data,
+            Err(e) => {
+                self.downloads.remove(&segment_id);
+                self.segment_request.resolve(&segment_id, Err(e.into()));
+                return;
+            }
+        };
+        match self.digests.verify(&segment_id, &data) {
+            Verification::Mismatch => {
+                let rejected = download.reject_contributors();
+                tracing::warn!(
+                    "Segment {:?} from peers {:?} does not match its digest, requesting it again",
+                    segment_id,
+                    rejected
+                );
+                // A lone sender is the culprit for sure, the others may have been unlucky.
+                let offence = match rejected.as_slice() {
+                    [_] => Offence::Mismatch,
+                    _ => Offence::SuspectedMismatch,
+                };
+                for peer in rejected {
+                    self.penalize(peer, offence);
+                }
+                self.schedule_download(&segment_id);
+            }
+            Verification::Unknown if self.is_strict() => {
+                tracing::warn!(
+                    "Segment {:?} is not listed in a signed manifest, discarding it",
+                    segment_id
+                );
+                self.downloads.remove(&segment_id);
+                self.segment_request
+                    .resolve(&segment_id, Err(RequestError::Unverified));
+            }
+            verification => {
+                if verification == Verification::Unknown {
+                    tracing::debug!("No digest to verify segment {:?} against", segment_id);
+                }
+                tracing::info!("Received segment {:?} ({} bytes)", segment_id, data.len());
+                self.downloads.remove(&segment_id);
+                self.segment_request.resolve(&segment_id, Ok(data.clone()));
+                // Seed what we downloaded.
+                self.store_segment(segment_id, data);
+            }
+        }
+    }
+
+    /// Update what `peer` holds from its announcement and ask it for the segments we miss.
+    /// Malformed announcements are rejected, so that they are not forwarded. Announcements of
+    /// peers we are not connected to are only forwarded.
+    fn handle_have(&mut self, peer: PeerId, data: &[u8]) -> MessageAcceptance {
+        let Some(held) = Holdings::decode(data) else {
+            tracing::warn!("Peer {:?} sent a malformed segment announcement", peer);
+            return MessageAcceptance::Reject;
+        };
+        if !self.swarm.is_connected(&peer) {
+            // Relayed from a peer we cannot ask for chunks, and would never forget as it never
+            // disconnects. Still forwarded, others may be connected to it.
+            tracing::debug!(
+                "Ignoring segment announcement of unconnected peer {:?}",
+                peer
+            );
+            return MessageAcceptance::Accept;
+        }
+        let wanted: Vec<String> = self
+            .downloads
+            .keys()
+            .filter(|segment_id| held.contains(segment_id))
+            .cloned()
+            .collect();
+        self.availability.update(peer, held);
+        for segment_id in wanted {
+            self.add_segment_candidate(&segment_id, peer);
+        }
+        MessageAcceptance::Accept
+    }
+
+    /// Announce every segment we can serve on the stream announcement topic.
+    fn announce(&mut self) {
+        let held: Holdings = self
+            .store
+            .ids()
+            .filter(|segment_id| !self.is_strict() || self.digests.contains(segment_id))
+            .collect();
+        match self
+            .swarm
+            .behaviour_mut()
+            .pubsub
+            .publish(self.topic.clone(), held.encode())
+        {
+            Ok(_) => tracing::debug!("Announced held segments {:?}", held),
+            Err(gossipsub::PublishError::NoPeersSubscribedToTopic) => {
+                tracing::debug!("No peer to announce held segments to");
+            }
+            Err(e) => tracing::warn!("Failed to announce held segments: {:?}", e),
+        }
+    }
+
+    /// Record `peer` as a holder of `segment_id`, requesting from it right away when idle.
+    fn add_segment_candidate(&mut self, segment_id: &str, peer: PeerId) {
+        let Some(download) = self.downloads.get_mut(segment_id) else {
+            return;
+        };
+        if download.add_holder(peer) {
+            self.schedule_download(segment_id);
+        }
+    }
+
+    /// Request the next chunks of the segment from the idle holders. When no holder is left
+    /// the download stays idle until one announces the segment, unless some chunks were
+    /// already received: then the request fails, as the download cannot be completed.
+    fn schedule_download(&mut self, segment_id: &str) {
+        if !self.segment_request.contains(segment_id) {
+            // Nobody is waiting for this segment anymore.
+            self.downloads.remove(segment_id);
+            return;
+        }
+        let Some(download) = self.downloads.get_mut(segment_id) else {
+            return;
+        };
+
+        download.rank_holders(|holders| rank_peers(&*self.selector, &self.reputation, holders));
+        for (peer, chunk) in download.schedule(Instant::now()) {
+            let request_id = self.swarm.behaviour_mut().segment.send_request(
+                &peer,
+                SegmentRequest {
+                    segment_id: segment_id.to_string(),
+                    chunk,
+                },
+            );
+            download.started(request_id, peer, chunk);
+            self.outbound.insert(request_id, segment_id.to_string());
+            tracing::debug!(
+                "Requested chunk {} of segment {:?} from peer {:?}",
+                chunk,
+                segment_id,
+                peer
+            );
+        }
+
+        if !download.is_stalled() {
+            return;
+        }
+        let received = download.received();
+        let total = download.total().unwrap_or_default();
+        if received == 0 {
+            tracing::debug!("No peer left to ask for segment {:?}", segment_id);
+            return;
+        }
+
+        tracing::warn!(
+            "No peer left to complete segment {:?}, {}/{} chunks received",
+            segment_id,
+            received,
+            total
+        );
+        self.downloads.remove(segment_id);
+        let error = ChunkError::Missing {
+            missing: total - received,
+            total,
+        };
+        self.segment_request.resolve(segment_id, Err(error.into()));
+    }
+
+    fn handle_housekeeping(&mut self) {
+        let now = Instant::now();
+        for expired in self.segment_request.expire(now) {
+            let (received, total) = self
+                .downloads
+                .get(&expired.segment_id)
+                .map_or((0, None), |d| (d.received(), d.total()));
+            tracing::info!(
+                "Request for segment {:?} expired after {:?}, {}/{} chunks received",
+                expired.segment_id,
+                expired.elapsed,
+                received,
+                total.unwrap_or_default()
+            );
+            let _ = expired.sender.send(Err(RequestError::Timeout {
+                elapsed: expired.elapsed,
+                received,
+                total,
+            }));
+            if expired.abandoned {
+                self.cancel_download(&expired.segment_id);
+            }
+        }
+
+        if self.announce_at.is_some_and(|at| now >= at) {
+            self.announce_at = Some(now + ANNOUNCE_INTERVAL);
+            self.announce();
+        }
+
+        // Race slow chunks against other holders.
+        let segment_ids: Vec<String> = self.downloads.keys().cloned().collect();
+        for segment_id in segment_ids {
+            self.schedule_download(&segment_id);
+        }
+
+        for tracker in self.config.trackers() {
+            let Some(state) = self.trackers.get_mut(&tracker.peer_id) else {
+                continue;
+            };
+            if state.redial_at.is_some_and(|at| now >= at) {
+                tracing::info!("Dialing rendezvous server at {:?} again", tracker.addr);
+                state.redial_at = Some(now + TRACKER_RETRY_DELAY);
+                if let Err(e) = self.swarm.dial(tracker.addr.clone()) {
+                    tracing::warn!("Failed to dial rendezvous server: {:?}", e);
+                }
+            }
+        }
+
+        let due: Vec<PeerId> = self
+            .trackers
+            .iter()
+            .filter(|(_, state)| state.register_at.is_some_and(|at| now >= at))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for tracker in due {
+            self.register(tracker);
+        }
+
+        let due: Vec<PeerId> = self
+            .trackers
+            .iter()
+            .filter(|(_, state)| state.listen_at.is_some_and(|at| now >= at))
+            .map(|(peer, _)| *peer)
+            .collect();
+        for tracker in due {
+            self.listen_via(tracker);
+        }
+
+        if now >= self.next_discovery {
+            let trackers: Vec<PeerId> = self
+                .trackers
+                .keys()
+                .filter(|peer| self.swarm.is_connected(peer))
+                .copied()
+                .collect();
+            for tracker in trackers {
+                self.discover(tracker);
+            }
+            self.last_discovery = Some(now);
+            self.next_discovery = now + self.config.discovery_interval();
+        }
+    }
+
+    /// Stop fetching a segment nobody waits for anymore. Responses to chunk requests already
+    /// sent are ignored when they arrive.
+    fn cancel_download(&mut self, segment_id: &str) {
+        if self.downloads.remove(segment_id).is_some() {
+            tracing::debug!("Cancelled download of segment {:?}", segment_id);
+        }
+    }
+
+    /// Register (or renew the registration of) our external addresses with a tracker.
+    fn register(&mut self, tracker: PeerId) {
+        let ttl = self.config.registration_ttl();
+        let result = self.swarm.behaviour_mut().rendezvous.register(
+            self.namespace.clone(),
+            tracker,
+            Some(ttl),
+        );
+        let Some(state) = self.trackers.get_mut(&tracker) else {
+            return;
+        };
+        match result {
+            // Wait for the outcome before scheduling the renewal.
+            Ok(()) => state.register_at = None,
+            Err(e) => {
+                tracing::warn!(
+                    "Cannot register with rendezvous node {:?} yet: {:?}",
+                    tracker,
+                    e
+                );
+                state.register_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+            }
+        }
+    }
+
+    /// Renew the registrations on every connected tracker, so that they learn our current
+    /// external addresses.
+    fn register_all(&mut self) {
+        let connected: Vec<PeerId> = self
+            .trackers
+            .keys()
+            .filter(|peer| self.swarm.is_connected(peer))
+            .copied()
+            .collect();
+        for tracker in connected {
+            self.register(tracker);
+        }
+    }
+
+    /// Reserve a slot on the relay of a tracker and listen on the resulting circuit.
+    fn listen_via(&mut self, tracker: PeerId) {
+        let Some(addr) = self
+            .config
+            .trackers()
+            .iter()
+            .find(|t| t.peer_id == tracker)
+            .map(|t| t.addr.clone().with(Protocol::P2pCircuit))
+        else {
+            return;
+        };
+        let Some(state) = self.trackers.get_mut(&tracker) else {
+            return;
+        };
+        if state.circuit.is_some() {
+            return;
+        }
+        match self.swarm.listen_on(addr.clone()) {
+            Ok(listener_id) => {
+                tracing::info!("Reserving a circuit on {:?}", addr);
+                state.circuit = Some(listener_id);
+                state.listen_at = None;
+            }
+            Err(e) => {
+                tracing::warn!("Cannot listen on circuit {:?}: {:?}", addr, e);
+                state.listen_at = Some(Instant::now() + TRACKER_RETRY_DELAY);
+            }
+        }
+    }
+
+    /// Whether `listener` is a circuit reserved on a tracker relay.
+    fn is_circuit(&self, listener: ListenerId) -> bool {
+        self.trackers
+            .values()
+            .any(|tracker| tracker.circuit == Some(listener))
+    }
+
+    /// Remove our registration from every tracker holding it.
+    fn unregister(&mut self) {
+        for (peer, state) in self.trackers.iter_mut() {
+            if state.registered {
+                tracing::info!("Unregistering from rendezvous node {:?}", peer);
+                self.swarm
+                    .behaviour_mut()
+                    .rendezvous
+                    .unregister(self.namespace.clone(), *peer);
+            }
+            state.registered = false;
+            state.register_at = None;
+        }
+    }
+
+    /// Ask a tracker for the peers of our namespace registered since the last discovery.
+    fn discover(&mut self, tracker: PeerId) {
+        let cookie = self
+            .trackers
+            .get(&tracker)
+            .and_then(|state| state.cookie.clone());
+        tracing::debug!("Discovering peers from rendezvous node {:?}", tracker);
+        self.swarm.behaviour_mut().rendezvous.discover(
+            Some(self.namespace.clone()),
+            cookie,
+            Some(self.config.discovery_limit()),
+            tracker,
+        );
+    }
+
     async fn handle_command(&mut self, command: Command) {
         match command {
             Command::Dial {
@@ -458,45 +1365,121 @@ impl EventLoop {
                 }
             }
             Command::ProvideSegment { segment_id, data } => {
-                let segment_id_clone = segment_id.clone();
-                let topic = IdentTopic::new(segment_id);
-                match self.swarm.behaviour_mut().pubsub.publish(topic, data) {
-                    Ok(message_id) => {
-                        tracing::info!(
-                            "Published segment {:?} with message {:?}",
-                            segment_id_clone,
-                            message_id
-                        );
-                    }
-                    Err(e) => {
-                        tracing::error!(
-                            "Failed to publish segment {:?} with error {:?}",
-                            segment_id_clone,
-                            e
-                        );
-                    }
+                if self.digests.verify(&segment_id, &data) == Verification::Mismatch {
+                    tracing::error!(
+                        "Segment {:?} does not match its digest, not providing it",
+                        segment_id
+                    );
+                    return;
                 }
+                // Keep the bytes to serve segment requests, the stream topic only announces
+                // that we hold them.
+                self.store_segment(segment_id, data);
             }
-            Command::RequestSegment { segment_id, sender } => {
-                let segment_id_clone = segment_id.clone();
-                let topic = IdentTopic::new(segment_id);
-                match self.swarm.behaviour_mut().pubsub.subscribe(&topic) {
-                    Ok(_) => {
-                        tracing::info!("Subscribed to topic {:?}", topic);
-                        self.segment_request.insert(segment_id_clone, sender);
+            Command::RequestSegment {
+                request_id,
+                segment_id,
+                deadline,
+                sender,
+            } => {
+                if let Ok(sequence) = segment_id.parse() {
+                    self.store.set_playhead(sequence);
+                }
+                if let Some(data) = self.store.get(&segment_id) {
+                    let _ = sender.send(Ok(data.to_vec()));
+                    return;
+                }
+                if self.is_strict() && !self.digests.contains(&segment_id) {
+                    // Whatever peers send could not be trusted.
+                    let _ = sender.send(Err(RequestError::Unverified));
+                    return;
+                }
+                if !self
+                    .segment_request
+                    .insert(segment_id.clone(), request_id, deadline, sender)
+                {
+                    // Another caller is already waiting for this segment, share its download.
+                    tracing::debug!("Joined pending request for segment {:?}", segment_id);
+                    return;
+                }
+                // Ask the peers that announced the segment, others are added as they do.
+                let holders: Vec<PeerId> = self
+                    .availability
+                    .holders(&segment_id)
+                    .into_iter()
+                    .filter(|peer| self.swarm.is_connected(peer))
+                    .collect();
+                self.downloads
+                    .insert(segment_id.clone(), SegmentDownload::new(holders));
+                self.schedule_download(&segment_id);
+            }
+            Command::AddSegmentDigest { segment_id, digest } => {
+                if self.is_strict() {
+                    tracing::error!(
+                        "Ignoring unsigned digest of segment {:?}, a publisher key is set",
+                        segment_id
+                    );
+                    return;
+                }
+                self.expect_digest(segment_id, digest);
+            }
+            Command::AddManifest {
+                manifest,
+                signature,
+                sender,
+            } => {
+                let Some(publisher) = self.config.publisher_key() else {
+                    let _ = sender.send(Err(ManifestError::NoPublisherKey));
+                    return;
+                };
+                match verify_manifest(publisher, &manifest, &signature) {
+                    Ok(entries) => {
+                        let count = entries.len();
+                        tracing::info!("Accepted manifest of {} segments", count);
+                        for (segment_id, digest) in entries {
+                            self.expect_digest(segment_id, digest);
+                        }
+                        let _ = sender.send(Ok(count));
                     }
                     Err(e) => {
-                        tracing::error!(
-                            "Failed to subscribe to topic {:?} with error {:?}",
-                            topic,
-                            e
-                        );
-                        let _ = sender.send(Err(e.into()));
+                        tracing::error!("Rejected segment manifest: {}", e);
+                        let _ = sender.send(Err(e));
                     }
                 }
             }
-            Command::Quit => {
-                tracing::info!("Shutting down the network event loop");
+            Command::CancelRequest { request_id } => {
+                let Some(segment_id) = self.segment_request.cancel(request_id) else {
+                    // Already answered.
+                    return;
+                };
+                if self.segment_request.contains(&segment_id) {
+                    tracing::debug!(
+                        "Cancelled a request for segment {:?}, others still wait for it",
+                        segment_id
+                    );
+                    return;
+                }
+                tracing::info!("Cancelled request for segment {:?}", segment_id);
+                self.cancel_download(&segment_id);
+            }
+            Command::Stats { sender } => {
+                let nat_status = match self.swarm.behaviour().autonat.nat_status() {
+                    autonat::NatStatus::Public(_) => "public",
+                    autonat::NatStatus::Private => "private",
+                    autonat::NatStatus::Unknown => "unknown",
+                };
+                let stats = ClientStats {
+                    connected_peers: self.swarm.connected_peers().count() as u32,
+                    stored_segments: self.store.len() as u32,
+                    stored_bytes: self.store.size() as f64,
+                    nat_status: nat_status.to_string(),
+                    ..self.stats.clone()
+                };
+                let _ = sender.send(stats);
+            }
+            Command::Quit { sender } => {
+                // Handled by the run loop, which stops after shutting down.
+                let _ = sender.send(());
             }
         }
     }
@@ -514,37 +1497,108 @@ pub enum Command {
         data: Vec<u8>,
     },
     RequestSegment {
+        request_id: u32,
         segment_id: String,
+        deadline: Instant,
         sender: oneshot::Sender<Result<Vec<u8>, RequestError>>,
     },
-    Quit,
+    AddSegmentDigest {
+        segment_id: String,
+        digest: SegmentDigest,
+    },
+    AddManifest {
+        manifest: String,
+        signature: Vec<u8>,
+        sender: oneshot::Sender<Result<usize, ManifestError>>,
+    },
+    CancelRequest {
+        request_id: u32,
+    },
+    Stats {
+        sender: oneshot::Sender<ClientStats>,
+    },
+    Quit {
+        sender: oneshot::Sender<()>,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum RequestError {
-    Timeout,
-    SubscribeError(SubscriptionError),
+    /// The deadline passed before the whole segment was received.
+    Timeout {
+        elapsed: Duration,
+        received: u32,
+        total: Option<u32>,
+    },
+    /// Dropped to make room for newer requests.
+    Evicted,
+    Cancelled,
+    Shutdown,
+    Incomplete(ChunkError),
+    /// The segment is not listed in a manifest signed by the publisher.
+    Unverified,
 }
 
 impl From<RequestError> for JsError {
     fn from(error: RequestError) -> Self {
         match error {
-            RequestError::Timeout => JsError::new("Request timed out"),
-            RequestError::SubscribeError(e) => {
-                JsError::new(&format!("Subscription error: {:?}", e))
-            }
+            RequestError::Timeout {
+                elapsed,
+                received,
+                total: Some(total),
+            } => JsError::new(&format!(
+                "Request timed out after {}ms with {}/{} chunks received",
+                elapsed.as_millis(),
+                received,
+                total
+            )),
+            RequestError::Timeout { elapsed, .. } => JsError::new(&format!(
+                "Request timed out after {}ms, no peer sent the segment",
+                elapsed.as_millis()
+            )),
+            RequestError::Evicted => JsError::new("Request dropped, too many pending requests"),
+            RequestError::Cancelled => JsError::new("Request cancelled"),
+            RequestError::Shutdown => JsError::new("P2P client is shutting down"),
+            RequestError::Incomplete(e) => JsError::new(&format!("Incomplete segment: {}", e)),
+            RequestError::Unverified => JsError::new("Segment is not listed in a signed manifest"),
         }
     }
 }
 
-impl From<SubscriptionError> for RequestError {
-    fn from(error: SubscriptionError) -> Self {
-        RequestError::SubscribeError(error)
+impl From<ManifestError> for JsError {
+    fn from(error: ManifestError) -> Self {
+        JsError::new(&format!("Invalid manifest: {}", error))
     }
 }
 
+impl From<ChunkError> for RequestError {
+    fn from(error: ChunkError) -> Self {
+        RequestError::Incomplete(error)
+    }
+}
+
+type SegmentResult = Result<Vec<u8>, RequestError>;
+
+struct Waiter {
+    request_id: u32,
+    sender: oneshot::Sender<SegmentResult>,
+    requested_at: Instant,
+    deadline: Instant,
+}
+
+/// A waiter whose deadline passed before its segment arrived.
+pub struct ExpiredWaiter {
+    pub segment_id: String,
+    pub sender: oneshot::Sender<SegmentResult>,
+    pub elapsed: Duration,
+    /// Nobody else waits for the segment, its download can stop.
+    pub abandoned: bool,
+}
+
+/// Pending segment requests. Every caller asking for the same segment waits on the same entry
+/// and receives the same result, unless its own deadline passes first.
 pub struct SegmentRequestCache {
-    requests: HashMap<String, oneshot::Sender<Result<Vec<u8>, RequestError>>>,
+    requests: HashMap<String, Vec<Waiter>>,
     order: VecDeque<String>,
     capacity: usize,
 }
@@ -558,27 +1612,244 @@ impl SegmentRequestCache {
         }
     }
 
-    pub fn insert(&mut self, key: String, value: oneshot::Sender<Result<Vec<u8>, RequestError>>) {
-        // Perform the insertion
-        self.requests.insert(key.clone(), value);
-        self.order.push_back(key.clone());
+    /// Add a waiter for `key`. Returns `true` if nobody was waiting for it yet, i.e. the
+    /// segment still has to be fetched.
+    pub fn insert(
+        &mut self,
+        key: String,
+        request_id: u32,
+        deadline: Instant,
+        sender: oneshot::Sender<SegmentResult>,
+    ) -> bool {
+        let waiter = Waiter {
+            request_id,
+            sender,
+            requested_at: Instant::now(),
+            deadline,
+        };
+        if let Some(waiters) = self.requests.get_mut(&key) {
+            waiters.retain(|w| !w.sender.is_canceled());
+            waiters.push(waiter);
+            return false;
+        }
+
+        self.requests.insert(key.clone(), vec![waiter]);
+        self.order.push_back(key);
 
         // Check for capacity overflow and remove the oldest item if necessary
-        if self.order.len() > self.capacity {
-            if let Some(oldest_key) = self.order.pop_front() {
-                if let Some(channel) = self.requests.remove(&oldest_key) {
-                    // The channel has been popped, return the Timeout Erro
-                    let _ = channel.send(Err(RequestError::Timeout));
-                }
-            }
+        if self.order.len() > self.capacity
+            && let Some(oldest_key) = self.order.pop_front()
+        {
+            self.resolve(&oldest_key, Err(RequestError::Evicted));
+        }
+        true
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.requests.contains_key(key)
+    }
+
+    /// Send the result to every waiter of `key`. Returns the number of waiters notified.
+    pub fn resolve(&mut self, key: &str, result: SegmentResult) -> usize {
+        let Some(waiters) = self.requests.remove(key) else {
+            return 0;
+        };
+        self.order.retain(|k| k != key); // Remove the key from the order tracking
+        waiters
+            .into_iter()
+            .filter(|waiter| !waiter.sender.is_canceled())
+            .map(|waiter| waiter.sender.send(result.clone()))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Reject the waiter of `request_id` as cancelled, the other waiters of its segment keep
+    /// waiting. Returns the segment it waited for, `None` if it was already answered.
+    pub fn cancel(&mut self, request_id: u32) -> Option<String> {
+        let (key, waiters) = self
+            .requests
+            .iter_mut()
+            .find(|(_, waiters)| waiters.iter().any(|w| w.request_id == request_id))?;
+        let key = key.clone();
+        let index = waiters.iter().position(|w| w.request_id == request_id)?;
+        let waiter = waiters.remove(index);
+        let _ = waiter.sender.send(Err(RequestError::Cancelled));
+        if waiters.is_empty() {
+            self.requests.remove(&key);
+            self.order.retain(|k| *k != key);
         }
+        Some(key)
+    }
+
+    /// Remove the waiters whose deadline passed. Segments left without waiters are dropped.
+    pub fn expire(&mut self, now: Instant) -> Vec<ExpiredWaiter> {
+        let mut expired = Vec::new();
+        for (segment_id, waiters) in self.requests.iter_mut() {
+            let (late, on_time): (Vec<_>, Vec<_>) =
+                waiters.drain(..).partition(|w| w.deadline <= now);
+            *waiters = on_time;
+            let abandoned = waiters.is_empty();
+            expired.extend(late.into_iter().map(|w| ExpiredWaiter {
+                segment_id: segment_id.clone(),
+                sender: w.sender,
+                elapsed: now.duration_since(w.requested_at),
+                abandoned,
+            }));
+        }
+
+        self.requests.retain(|_, waiters| !waiters.is_empty());
+        let requests = &self.requests;
+        self.order.retain(|k| requests.contains_key(k));
+        expired
     }
 
-    pub fn remove(&mut self, key: &str) -> Option<oneshot::Sender<Result<Vec<u8>, RequestError>>> {
-        if let Some(sender) = self.requests.remove(key) {
-            self.order.retain(|k| k != key); // Remove the key from the order tracking
-            return Some(sender);
+    pub fn fail_all(&mut self, error: RequestError) {
+        let keys: Vec<String> = self.requests.keys().cloned().collect();
+        for key in keys {
+            self.resolve(&key, Err(error.clone()));
         }
-        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Receiver = oneshot::Receiver<SegmentResult>;
+
+    fn wait(
+        cache: &mut SegmentRequestCache,
+        key: &str,
+        request_id: u32,
+        timeout: Duration,
+    ) -> (bool, Receiver) {
+        let (sender, receiver) = oneshot::channel();
+        let first = cache.insert(
+            key.to_string(),
+            request_id,
+            Instant::now() + timeout,
+            sender,
+        );
+        (first, receiver)
+    }
+
+    fn answer(receiver: &mut Receiver) -> Option<SegmentResult> {
+        receiver
+            .try_recv()
+            .expect("sender dropped without an answer")
+    }
+
+    #[test]
+    fn cancels_a_single_waiter() {
+        let mut cache = SegmentRequestCache::new(10);
+        let (_, mut cancelled) = wait(&mut cache, "1", 1, Duration::from_secs(5));
+        let (_, mut other) = wait(&mut cache, "1", 2, Duration::from_secs(5));
+
+        assert_eq!(cache.cancel(1), Some("1".to_string()));
+        assert!(matches!(
+            answer(&mut cancelled),
+            Some(Err(RequestError::Cancelled))
+        ));
+        assert!(answer(&mut other).is_none());
+        assert!(cache.contains("1"));
+
+        assert_eq!(cache.cancel(1), None);
+        assert_eq!(cache.cancel(2), Some("1".to_string()));
+        assert!(!cache.contains("1"));
+        // The segment left no trace, a new request fetches it again.
+        assert!(wait(&mut cache, "1", 3, Duration::from_secs(5)).0);
+    }
+
+    #[test]
+    fn shares_a_pending_request_between_waiters() {
+        let mut cache = SegmentRequestCache::new(10);
+        let (first, mut a) = wait(&mut cache, "1", 1, Duration::from_secs(5));
+        let (second, mut b) = wait(&mut cache, "1", 2, Duration::from_secs(5));
+        let (third, dropped) = wait(&mut cache, "1", 3, Duration::from_secs(5));
+        assert!(first);
+        assert!(!second && !third);
+
+        // Waiters that went away are not notified.
+        drop(dropped);
+        assert_eq!(cache.resolve("1", Ok(vec![1, 2, 3])), 2);
+        assert!(matches!(answer(&mut a), Some(Ok(data)) if data == [1, 2, 3]));
+        assert!(matches!(answer(&mut b), Some(Ok(data)) if data == [1, 2, 3]));
+        assert!(!cache.contains("1"));
+        assert_eq!(cache.resolve("1", Ok(Vec::new())), 0);
+    }
+
+    #[test]
+    fn prunes_cancelled_waiters_when_joining() {
+        let mut cache = SegmentRequestCache::new(10);
+        let (_, gone) = wait(&mut cache, "1", 1, Duration::from_secs(5));
+        drop(gone);
+        let (first, _waiting) = wait(&mut cache, "1", 2, Duration::from_secs(5));
+        assert!(!first);
+        assert_eq!(cache.requests["1"].len(), 1);
+    }
+
+    #[test]
+    fn evicts_the_oldest_request_at_capacity() {
+        let mut cache = SegmentRequestCache::new(2);
+        let (_, mut oldest) = wait(&mut cache, "1", 1, Duration::from_secs(5));
+        let (_, mut joined) = wait(&mut cache, "1", 2, Duration::from_secs(5));
+        let (_, mut newer) = wait(&mut cache, "2", 3, Duration::from_secs(5));
+        // Joining a pending request does not count against the capacity.
+        assert!(answer(&mut oldest).is_none());
+
+        let (_, mut newest) = wait(&mut cache, "3", 4, Duration::from_secs(5));
+        assert!(matches!(
+            answer(&mut oldest),
+            Some(Err(RequestError::Evicted))
+        ));
+        assert!(matches!(
+            answer(&mut joined),
+            Some(Err(RequestError::Evicted))
+        ));
+        assert!(!cache.contains("1"));
+        assert!(answer(&mut newer).is_none());
+        assert!(answer(&mut newest).is_none());
+    }
+
+    #[test]
+    fn fails_every_waiter_on_shutdown() {
+        let mut cache = SegmentRequestCache::new(10);
+        let (_, mut a) = wait(&mut cache, "1", 1, Duration::from_secs(5));
+        let (_, mut b) = wait(&mut cache, "2", 2, Duration::from_secs(5));
+        cache.fail_all(RequestError::Shutdown);
+        assert!(matches!(answer(&mut a), Some(Err(RequestError::Shutdown))));
+        assert!(matches!(answer(&mut b), Some(Err(RequestError::Shutdown))));
+        assert!(!cache.contains("1") && !cache.contains("2"));
+    }
+
+    #[test]
+    fn expires_each_waiter_at_its_own_deadline() {
+        let mut cache = SegmentRequestCache::new(10);
+        let (_, _short) = wait(&mut cache, "1", 1, Duration::from_secs(1));
+        let (_, mut long) = wait(&mut cache, "1", 2, Duration::from_secs(3));
+        let (_, _other) = wait(&mut cache, "2", 3, Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert!(cache.expire(now).is_empty());
+
+        let mut expired = cache.expire(now + Duration::from_secs(2));
+        expired.sort_by(|a, b| a.segment_id.cmp(&b.segment_id));
+        let expired: Vec<(&str, bool)> = expired
+            .iter()
+            .map(|e| (e.segment_id.as_str(), e.abandoned))
+            .collect();
+        // Segment 1 is still awaited, segment 2 can stop downloading.
+        assert_eq!(expired, [("1", false), ("2", true)]);
+        assert!(cache.contains("1"));
+        assert!(!cache.contains("2"));
+        assert!(answer(&mut long).is_none());
+
+        let expired = cache.expire(now + Duration::from_secs(4));
+        assert_eq!(expired.len(), 1);
+        assert!(expired[0].abandoned);
+        assert!(expired[0].elapsed >= Duration::from_secs(3));
+        assert!(!cache.contains("1"));
+        // Expired requests no longer count against the capacity.
+        assert!(cache.order.is_empty());
     }
 }
@@ -0,0 +1,207 @@
+use libp2p::identity::ed25519;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+/// Number of expected digests kept, the oldest are forgotten first.
+pub const DEFAULT_DIGEST_CAPACITY: usize = 4096;
+
+pub type SegmentDigest = [u8; 32];
+
+/// Outcome of checking segment bytes against the expected digest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    Mismatch,
+    /// No digest is known for the segment, its bytes cannot be checked.
+    Unknown,
+}
+
+/// SHA-256 digests of the segments, as published by the origin alongside the playlist.
+pub struct SegmentDigests {
+    digests: HashMap<String, SegmentDigest>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl SegmentDigests {
+    pub fn new(capacity: usize) -> Self {
+        SegmentDigests {
+            digests: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Record the expected digest of a segment, replacing any previous one.
+    pub fn insert(&mut self, segment_id: String, digest: SegmentDigest) {
+        if self.digests.insert(segment_id.clone(), digest).is_none() {
+            self.order.push_back(segment_id);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.digests.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn contains(&self, segment_id: &str) -> bool {
+        self.digests.contains_key(segment_id)
+    }
+
+    pub fn verify(&self, segment_id: &str, data: &[u8]) -> Verification {
+        match self.digests.get(segment_id) {
+            Some(expected) if *expected == digest(data) => Verification::Valid,
+            Some(_) => Verification::Mismatch,
+            None => Verification::Unknown,
+        }
+    }
+}
+
+pub fn digest(data: &[u8]) -> SegmentDigest {
+    Sha256::digest(data).into()
+}
+
+/// Parse a hex encoded SHA-256 digest.
+pub fn parse_digest(hex_digest: &str) -> Option<SegmentDigest> {
+    let mut digest = [0; 32];
+    hex::decode_to_slice(hex_digest.trim(), &mut digest).ok()?;
+    Some(digest)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManifestError {
+    /// No publisher key is configured to verify manifests with.
+    NoPublisherKey,
+    BadSignature,
+    /// The line is not a segment id followed by a hex encoded SHA-256 digest.
+    Malformed {
+        line: usize,
+    },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::NoPublisherKey => write!(f, "no publisher key is configured"),
+            ManifestError::BadSignature => {
+                write!(f, "manifest signature does not match the publisher key")
+            }
+            ManifestError::Malformed { line } => write!(f, "manifest line {line} is malformed"),
+        }
+    }
+}
+
+/// Check the publisher signature of a segment manifest and return its entries.
+///
+/// A manifest is UTF-8 text with one `<segment id> <hex sha256>` entry per line, signed as a
+/// whole with the publisher ed25519 key.
+pub fn verify_manifest(
+    publisher: &ed25519::PublicKey,
+    manifest: &str,
+    signature: &[u8],
+) -> Result<Vec<(String, SegmentDigest)>, ManifestError> {
+    if !publisher.verify(manifest.as_bytes(), signature) {
+        return Err(ManifestError::BadSignature);
+    }
+    manifest
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let mut fields = line.split_whitespace();
+            match (
+                fields.next(),
+                fields.next().and_then(parse_digest),
+                fields.next(),
+            ) {
+                (Some(segment_id), Some(digest), None) => Ok((segment_id.to_string(), digest)),
+                _ => Err(ManifestError::Malformed { line: index + 1 }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(keypair: &ed25519::Keypair, manifest: &str) -> Vec<u8> {
+        keypair.sign(manifest.as_bytes())
+    }
+
+    #[test]
+    fn verifies_signed_manifests() {
+        let publisher = ed25519::Keypair::generate();
+        let manifest = format!(
+            "10 {}\n\n11 {}\n",
+            hex::encode(digest(b"ten")),
+            hex::encode(digest(b"eleven"))
+        );
+
+        let entries =
+            verify_manifest(&publisher.public(), &manifest, &sign(&publisher, &manifest)).unwrap();
+        assert_eq!(
+            entries,
+            [
+                ("10".to_string(), digest(b"ten")),
+                ("11".to_string(), digest(b"eleven"))
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let publisher = ed25519::Keypair::generate();
+        let manifest = format!("10 {}\n", hex::encode(digest(b"ten")));
+        let signature = sign(&publisher, &manifest);
+
+        let tampered = format!("10 {}\n", hex::encode(digest(b"other")));
+        assert_eq!(
+            verify_manifest(&publisher.public(), &tampered, &signature),
+            Err(ManifestError::BadSignature)
+        );
+        let other = ed25519::Keypair::generate();
+        assert_eq!(
+            verify_manifest(&other.public(), &manifest, &signature),
+            Err(ManifestError::BadSignature)
+        );
+        assert_eq!(
+            verify_manifest(&publisher.public(), &manifest, &[]),
+            Err(ManifestError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let publisher = ed25519::Keypair::generate();
+        let valid = format!("10 {}", hex::encode(digest(b"ten")));
+        for (manifest, line) in [
+            (format!("{valid}\n11\n"), 2),
+            (format!("{valid}\n11 not-hex\n"), 2),
+            (format!("11 {}\n{valid}\n", hex::encode([0; 16])), 1),
+            (format!("\n{valid} extra\n"), 2),
+        ] {
+            assert_eq!(
+                verify_manifest(&publisher.public(), &manifest, &sign(&publisher, &manifest)),
+                Err(ManifestError::Malformed { line }),
+                "{manifest:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn verifies_segments_against_known_digests() {
+        let mut digests = SegmentDigests::new(1);
+        digests.insert("10".to_string(), digest(b"ten"));
+        assert_eq!(digests.verify("10", b"ten"), Verification::Valid);
+        assert_eq!(digests.verify("10", b"eleven"), Verification::Mismatch);
+
+        // The oldest digest is forgotten beyond the capacity.
+        digests.insert("11".to_string(), digest(b"eleven"));
+        assert_eq!(digests.verify("10", b"ten"), Verification::Unknown);
+        assert!(digests.contains("11"));
+    }
+}
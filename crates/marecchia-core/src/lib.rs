@@ -1,5 +1,22 @@
+#![cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+
+mod availability;
 mod behaviour;
+mod chunk;
+// The browser transports only build for wasm32, the rest of the crate is tested natively.
+#[cfg(target_arch = "wasm32")]
 mod client;
+mod config;
+mod download;
 mod event_loop;
+mod integrity;
+mod protocol;
+mod reputation;
+mod selection;
+mod stats;
+mod store;
 
+#[cfg(target_arch = "wasm32")]
 pub use client::{P2PClient, new_p2p_client};
+pub use config::P2PConfig;
+pub use stats::ClientStats;
@@ -0,0 +1,171 @@
+use async_trait::async_trait;
+use libp2p::{
+    StreamProtocol,
+    futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    request_response,
+};
+use std::io;
+
+use super::chunk::{CHUNK_SIZE, Chunk};
+
+pub const SEGMENT_PROTOCOL: StreamProtocol = StreamProtocol::new("/marecchia/segment/1");
+
+// Segment ids are HLS sequence numbers or short strings, anything bigger is garbage.
+const MAX_REQUEST_SIZE: usize = 1024;
+const MAX_RESPONSE_SIZE: usize = CHUNK_SIZE;
+
+const RESPONSE_NOT_FOUND: u8 = 0;
+const RESPONSE_FOUND: u8 = 1;
+
+/// Ask a peer for one chunk of a segment it holds.
+#[derive(Debug, Clone)]
+pub struct SegmentRequest {
+    pub segment_id: String,
+    pub chunk: u32,
+}
+
+#[derive(Debug)]
+pub enum SegmentResponse {
+    Found(Chunk),
+    NotFound,
+}
+
+/// Length-prefixed binary codec for the segment protocol.
+///
+/// Request: `u32 chunk index | u32 len | segment id`.
+/// Response: `u8 tag | u32 chunk index | u32 total chunks | u32 segment len | u32 len | chunk
+/// bytes` (only the tag when not found).
+#[derive(Debug, Clone, Default)]
+pub struct SegmentCodec;
+
+#[async_trait]
+impl request_response::Codec for SegmentCodec {
+    type Protocol = StreamProtocol;
+    type Request = SegmentRequest;
+    type Response = SegmentResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<SegmentRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let chunk = read_u32(io).await?;
+        let bytes = read_length_prefixed(io, MAX_REQUEST_SIZE).await?;
+        let segment_id =
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(SegmentRequest { segment_id, chunk })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<SegmentResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut tag = [0u8; 1];
+        io.read_exact(&mut tag).await?;
+        match tag[0] {
+            RESPONSE_NOT_FOUND => Ok(SegmentResponse::NotFound),
+            RESPONSE_FOUND => {
+                let index = read_u32(io).await?;
+                let total = read_u32(io).await?;
+                let segment_len = read_u32(io).await?;
+                let data = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+                Ok(SegmentResponse::Found(Chunk {
+                    index,
+                    total,
+                    segment_len,
+                    data,
+                }))
+            }
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown segment response tag {other}"),
+            )),
+        }
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        req: SegmentRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&req.chunk.to_be_bytes()).await?;
+        write_length_prefixed(io, req.segment_id.as_bytes(), MAX_REQUEST_SIZE).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        res: SegmentResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        match res {
+            SegmentResponse::NotFound => io.write_all(&[RESPONSE_NOT_FOUND]).await,
+            SegmentResponse::Found(chunk) => {
+                io.write_all(&[RESPONSE_FOUND]).await?;
+                io.write_all(&chunk.index.to_be_bytes()).await?;
+                io.write_all(&chunk.total.to_be_bytes()).await?;
+                io.write_all(&chunk.segment_len.to_be_bytes()).await?;
+                write_length_prefixed(io, &chunk.data, MAX_RESPONSE_SIZE).await
+            }
+        }
+    }
+}
+
+async fn read_u32<T>(io: &mut T) -> io::Result<u32>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut buf = [0u8; 4];
+    io.read_exact(&mut buf).await?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+async fn read_length_prefixed<T>(io: &mut T, max_size: usize) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let len = read_u32(io).await? as usize;
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes exceeds the maximum of {max_size} bytes"),
+        ));
+    }
+
+    let mut buf = vec![0u8; len];
+    io.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn write_length_prefixed<T>(io: &mut T, data: &[u8], max_size: usize) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    if data.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "message of {} bytes exceeds the maximum of {max_size} bytes",
+                data.len()
+            ),
+        ));
+    }
+
+    io.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    io.write_all(data).await?;
+    io.flush().await
+}
@@ -0,0 +1,185 @@
+use libp2p::PeerId;
+use std::{collections::HashMap, time::Duration};
+
+/// Peers whose score drops to this value are banned for the rest of the session.
+pub const BAN_SCORE: f64 = -50.0;
+/// Successful transfers stop raising the score past this many, so that a peer cannot bank
+/// credit to offset misbehaving later.
+const MAX_REWARDED_SUCCESSES: u32 = 20;
+/// Weight of the latest sample in the throughput and round-trip time moving averages.
+const SMOOTHING: f64 = 0.3;
+
+/// Misbehaviour of a peer serving segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// The peer alone sent a segment not matching its digest.
+    Mismatch,
+    /// The peer sent part of a segment not matching its digest, together with other peers.
+    SuspectedMismatch,
+    /// The peer sent a chunk that does not fit the segment layout it announced itself.
+    Malformed,
+    /// The peer did not answer a chunk request in time.
+    Timeout,
+}
+
+impl Offence {
+    fn penalty(self) -> f64 {
+        match self {
+            Offence::Mismatch => 20.0,
+            Offence::SuspectedMismatch => 5.0,
+            Offence::Malformed => 10.0,
+            Offence::Timeout => 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerRecord {
+    successes: u32,
+    mismatches: u32,
+    timeouts: u32,
+    penalty: f64,
+    /// Smoothed transfer rate of the chunks received, in bytes per second.
+    throughput: Option<f64>,
+    /// Smoothed round-trip time measured by ping.
+    rtt: Option<Duration>,
+}
+
+/// What the local node learned about its peers during this session: how well they served
+/// segments and how fast they are.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    peers: HashMap<PeerId, PeerRecord>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a chunk of `bytes` received from `peer` in `elapsed`. Returns the new score.
+    pub fn record_success(&mut self, peer: PeerId, bytes: usize, elapsed: Duration) -> f64 {
+        let record = self.peers.entry(peer).or_default();
+        record.successes += 1;
+        // Empty chunks say nothing about the transfer rate.
+        let secs = elapsed.as_secs_f64();
+        if bytes > 0 && secs > 0.0 {
+            let sample = bytes as f64 / secs;
+            record.throughput = Some(match record.throughput {
+                Some(average) => average + SMOOTHING * (sample - average),
+                None => sample,
+            });
+        }
+        self.score(&peer)
+    }
+
+    pub fn record_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        let record = self.peers.entry(peer).or_default();
+        record.rtt = Some(match record.rtt {
+            Some(average) => average.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING),
+            None => rtt,
+        });
+    }
+
+    /// Record a misbehaviour of `peer`. Returns the new score.
+    pub fn penalize(&mut self, peer: PeerId, offence: Offence) -> f64 {
+        let record = self.peers.entry(peer).or_default();
+        match offence {
+            Offence::Mismatch | Offence::SuspectedMismatch => record.mismatches += 1,
+            Offence::Timeout => record.timeouts += 1,
+            Offence::Malformed => {}
+        }
+        record.penalty += offence.penalty();
+        self.score(&peer)
+    }
+
+    /// Score of a peer, 0 for unknown peers. Positive for peers that served us well.
+    pub fn score(&self, peer: &PeerId) -> f64 {
+        self.peers.get(peer).map_or(0.0, |record| {
+            f64::from(record.successes.min(MAX_REWARDED_SUCCESSES)) - record.penalty
+        })
+    }
+
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.score(peer) <= BAN_SCORE
+    }
+
+    /// Smoothed rate at which `peer` sent us chunks, in bytes per second.
+    pub fn throughput(&self, peer: &PeerId) -> Option<f64> {
+        self.peers.get(peer).and_then(|record| record.throughput)
+    }
+
+    /// Smoothed round-trip time to `peer`.
+    pub fn rtt(&self, peer: &PeerId) -> Option<Duration> {
+        self.peers.get(peer).and_then(|record| record.rtt)
+    }
+
+    /// Successful transfers, hash mismatches and timeouts recorded for `peer`.
+    pub fn counters(&self, peer: &PeerId) -> (u32, u32, u32) {
+        self.peers.get(peer).map_or((0, 0, 0), |record| {
+            (record.successes, record.mismatches, record.timeouts)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewards_successes_up_to_a_cap() {
+        let peer = PeerId::random();
+        let mut reputation = Reputation::new();
+        assert_eq!(reputation.score(&peer), 0.0);
+        for _ in 0..MAX_REWARDED_SUCCESSES + 5 {
+            reputation.record_success(peer, 1000, Duration::from_millis(10));
+        }
+        assert_eq!(reputation.score(&peer), f64::from(MAX_REWARDED_SUCCESSES));
+        assert_eq!(
+            reputation.counters(&peer),
+            (MAX_REWARDED_SUCCESSES + 5, 0, 0)
+        );
+    }
+
+    #[test]
+    fn bans_peers_once_penalties_reach_the_ban_score() {
+        let peer = PeerId::random();
+        let mut reputation = Reputation::new();
+        reputation.record_success(peer, 1000, Duration::from_millis(10));
+        assert_eq!(reputation.penalize(peer, Offence::Mismatch), -19.0);
+        assert_eq!(reputation.penalize(peer, Offence::Malformed), -29.0);
+        assert_eq!(reputation.penalize(peer, Offence::Mismatch), -49.0);
+        assert!(!reputation.is_banned(&peer));
+        reputation.penalize(peer, Offence::Timeout);
+        assert!(reputation.is_banned(&peer));
+        assert_eq!(reputation.counters(&peer), (1, 2, 1));
+    }
+
+    #[test]
+    fn smooths_the_throughput() {
+        let peer = PeerId::random();
+        let mut reputation = Reputation::new();
+        assert_eq!(reputation.throughput(&peer), None);
+        reputation.record_success(peer, 1000, Duration::from_secs(1));
+        assert_eq!(reputation.throughput(&peer), Some(1000.0));
+        reputation.record_success(peer, 2000, Duration::from_secs(1));
+        assert_eq!(reputation.throughput(&peer), Some(1300.0));
+
+        // Instant and empty transfers are not samples, they only count as successes.
+        reputation.record_success(peer, 1000, Duration::ZERO);
+        reputation.record_success(peer, 0, Duration::from_secs(1));
+        assert_eq!(reputation.throughput(&peer), Some(1300.0));
+        assert_eq!(reputation.counters(&peer), (4, 0, 0));
+    }
+
+    #[test]
+    fn smooths_the_rtt() {
+        let peer = PeerId::random();
+        let mut reputation = Reputation::new();
+        reputation.record_rtt(peer, Duration::from_millis(100));
+        assert_eq!(reputation.rtt(&peer), Some(Duration::from_millis(100)));
+        reputation.record_rtt(peer, Duration::from_millis(200));
+        assert_eq!(reputation.rtt(&peer), Some(Duration::from_millis(130)));
+        assert_eq!(reputation.score(&peer), 0.0);
+    }
+}
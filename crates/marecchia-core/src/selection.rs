@@ -0,0 +1,233 @@
+use libp2p::PeerId;
+use std::{cmp::Ordering, time::Duration};
+
+use super::chunk::CHUNK_SIZE;
+use super::reputation::Reputation;
+
+/// A peer holding a segment, with what is known about it.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub peer: PeerId,
+    pub rtt: Option<Duration>,
+    /// Bytes per second observed while downloading from the peer.
+    pub throughput: Option<f64>,
+    pub score: f64,
+}
+
+impl Candidate {
+    /// Expected time to receive a full chunk from the peer, if it was measured.
+    pub fn chunk_time(&self) -> Option<Duration> {
+        let throughput = self.throughput.filter(|t| *t > 0.0)?;
+        // Too slow to be represented is as good as never.
+        let transfer =
+            Duration::try_from_secs_f64(CHUNK_SIZE as f64 / throughput).unwrap_or(Duration::MAX);
+        Some(self.rtt.unwrap_or_default().saturating_add(transfer))
+    }
+}
+
+/// Policy deciding which holders of a segment are asked for its chunks first.
+pub trait PeerSelector {
+    /// Sort the candidates, most preferred first. Chunks are requested from the first idle
+    /// ones, the order of candidates left as is follows the order they announced the segment.
+    fn rank(&self, candidates: &mut [Candidate]);
+}
+
+/// Built-in selection policies, picked from JS by name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerSelection {
+    #[default]
+    Fastest,
+    LowestLatency,
+    Announced,
+}
+
+impl PeerSelection {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fastest" => Some(PeerSelection::Fastest),
+            "latency" => Some(PeerSelection::LowestLatency),
+            "announced" => Some(PeerSelection::Announced),
+            _ => None,
+        }
+    }
+
+    pub fn selector(self) -> Box<dyn PeerSelector> {
+        match self {
+            PeerSelection::Fastest => Box::new(Fastest),
+            PeerSelection::LowestLatency => Box::new(LowestLatency),
+            PeerSelection::Announced => Box::new(Announced),
+        }
+    }
+}
+
+/// Keep the announcement order, most recent announcer first.
+pub struct Announced;
+
+impl PeerSelector for Announced {
+    fn rank(&self, _: &mut [Candidate]) {}
+}
+
+/// Prefer the peers expected to deliver a chunk the soonest. Peers never downloaded from come
+/// next, by round-trip time, so that they get a chance to be measured.
+pub struct Fastest;
+
+impl PeerSelector for Fastest {
+    fn rank(&self, candidates: &mut [Candidate]) {
+        candidates.sort_by(|a, b| {
+            compare_known(a.chunk_time(), b.chunk_time()).then(compare_known(a.rtt, b.rtt))
+        });
+    }
+}
+
+/// Prefer the peers with the lowest round-trip time, then the best reputation.
+pub struct LowestLatency;
+
+impl PeerSelector for LowestLatency {
+    fn rank(&self, candidates: &mut [Candidate]) {
+        candidates.sort_by(|a, b| compare_known(a.rtt, b.rtt).then(b.score.total_cmp(&a.score)));
+    }
+}
+
+/// Order measured values ascending, unknown ones last.
+fn compare_known<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Reorder `peers` with `selector`, based on what `reputation` knows about them.
+pub fn rank_peers(selector: &dyn PeerSelector, reputation: &Reputation, peers: &mut [PeerId]) {
+    let mut candidates: Vec<Candidate> = peers
+        .iter()
+        .map(|peer| Candidate {
+            peer: *peer,
+            rtt: reputation.rtt(peer),
+            throughput: reputation.throughput(peer),
+            score: reputation.score(peer),
+        })
+        .collect();
+    selector.rank(&mut candidates);
+    for (peer, candidate) in peers.iter_mut().zip(candidates) {
+        *peer = candidate.peer;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reputation::Offence;
+
+    fn candidate(rtt_ms: Option<u64>, throughput: Option<f64>, score: f64) -> Candidate {
+        Candidate {
+            peer: PeerId::random(),
+            rtt: rtt_ms.map(Duration::from_millis),
+            throughput,
+            score,
+        }
+    }
+
+    fn ranked(selector: &dyn PeerSelector, candidates: &[Candidate]) -> Vec<PeerId> {
+        let mut ranked = candidates.to_vec();
+        selector.rank(&mut ranked);
+        ranked.iter().map(|c| c.peer).collect()
+    }
+
+    #[test]
+    fn estimates_the_chunk_time() {
+        let chunk_per_second = CHUNK_SIZE as f64;
+        assert_eq!(
+            candidate(Some(100), Some(chunk_per_second), 0.0).chunk_time(),
+            Some(Duration::from_millis(1100))
+        );
+        assert_eq!(candidate(Some(100), None, 0.0).chunk_time(), None);
+        assert_eq!(candidate(Some(100), Some(0.0), 0.0).chunk_time(), None);
+    }
+
+    #[test]
+    fn saturates_the_chunk_time_of_stalled_peers() {
+        for throughput in [1e-20, f64::MIN_POSITIVE] {
+            assert_eq!(
+                candidate(Some(100), Some(throughput), 0.0).chunk_time(),
+                Some(Duration::MAX)
+            );
+        }
+        let slow = Candidate {
+            rtt: Some(Duration::MAX),
+            ..candidate(None, Some(1.0), 0.0)
+        };
+        assert_eq!(slow.chunk_time(), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn ranks_the_fastest_then_unmeasured_peers_by_rtt() {
+        let fast = candidate(Some(200), Some(10.0 * CHUNK_SIZE as f64), 0.0);
+        let slow = candidate(Some(10), Some(CHUNK_SIZE as f64), 0.0);
+        let unmeasured_near = candidate(Some(5), None, 0.0);
+        let unknown = candidate(None, None, 0.0);
+        let candidates = [
+            unknown.clone(),
+            slow.clone(),
+            unmeasured_near.clone(),
+            fast.clone(),
+        ];
+        assert_eq!(
+            ranked(&Fastest, &candidates),
+            [fast.peer, slow.peer, unmeasured_near.peer, unknown.peer]
+        );
+    }
+
+    #[test]
+    fn ranks_by_rtt_then_score() {
+        let near = candidate(Some(10), None, 0.0);
+        let far = candidate(Some(100), None, 5.0);
+        let far_trusted = candidate(Some(100), None, 10.0);
+        let unknown = candidate(None, None, 20.0);
+        let candidates = [
+            unknown.clone(),
+            far.clone(),
+            far_trusted.clone(),
+            near.clone(),
+        ];
+        assert_eq!(
+            ranked(&LowestLatency, &candidates),
+            [near.peer, far_trusted.peer, far.peer, unknown.peer]
+        );
+        assert_eq!(
+            ranked(&Announced, &candidates),
+            candidates.iter().map(|c| c.peer).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ranks_peers_from_their_reputation() {
+        let (trusted, penalized, unknown) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut reputation = Reputation::new();
+        reputation.record_rtt(trusted, Duration::from_millis(50));
+        reputation.record_rtt(penalized, Duration::from_millis(50));
+        reputation.penalize(penalized, Offence::Timeout);
+
+        let mut peers = [unknown, penalized, trusted];
+        rank_peers(&LowestLatency, &reputation, &mut peers);
+        assert_eq!(peers, [trusted, penalized, unknown]);
+    }
+
+    #[test]
+    fn parses_selection_names() {
+        assert_eq!(
+            PeerSelection::from_name("fastest"),
+            Some(PeerSelection::Fastest)
+        );
+        assert_eq!(
+            PeerSelection::from_name("latency"),
+            Some(PeerSelection::LowestLatency)
+        );
+        assert_eq!(
+            PeerSelection::from_name("announced"),
+            Some(PeerSelection::Announced)
+        );
+        assert_eq!(PeerSelection::from_name("random"), None);
+    }
+}
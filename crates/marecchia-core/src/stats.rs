@@ -0,0 +1,63 @@
+use wasm_bindgen::prelude::*;
+
+/// Snapshot of the client counters, returned by `P2PClient.stats()`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default)]
+pub struct ClientStats {
+    pub(crate) connected_peers: u32,
+    pub(crate) stored_segments: u32,
+    pub(crate) stored_bytes: f64,
+    pub(crate) nat_status: String,
+    pub(crate) autonat_probes: u32,
+    pub(crate) autonat_probe_failures: u32,
+    pub(crate) hole_punches: u32,
+    pub(crate) hole_punch_failures: u32,
+}
+
+#[wasm_bindgen]
+impl ClientStats {
+    #[wasm_bindgen(getter)]
+    pub fn connected_peers(&self) -> u32 {
+        self.connected_peers
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stored_segments(&self) -> u32 {
+        self.stored_segments
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn stored_bytes(&self) -> f64 {
+        self.stored_bytes
+    }
+
+    /// Reachability learned through AutoNAT: `unknown`, `private` or `public`.
+    #[wasm_bindgen(getter)]
+    pub fn nat_status(&self) -> String {
+        self.nat_status.clone()
+    }
+
+    /// AutoNAT probes answered by other peers.
+    #[wasm_bindgen(getter)]
+    pub fn autonat_probes(&self) -> u32 {
+        self.autonat_probes
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn autonat_probe_failures(&self) -> u32 {
+        self.autonat_probe_failures
+    }
+
+    /// Relayed connections upgraded to direct ones by DCUtR. Always 0 in browsers for now, the
+    /// WebRTC transport cannot take the listener role a hole punch needs.
+    #[wasm_bindgen(getter)]
+    pub fn hole_punches(&self) -> u32 {
+        self.hole_punches
+    }
+
+    /// Failed DCUtR upgrades, in browsers every attempt fails for the reason above.
+    #[wasm_bindgen(getter)]
+    pub fn hole_punch_failures(&self) -> u32 {
+        self.hole_punch_failures
+    }
+}
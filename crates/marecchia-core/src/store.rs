@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+
+/// Bytes kept around by default for serving other peers.
+pub const DEFAULT_STORE_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// Recently downloaded or provided segments, bounded by their total size in bytes.
+///
+/// When full, segments behind the playhead are evicted first (farthest behind first), then the
+/// least recently used ones. Segment ids that are not HLS sequence numbers only follow LRU.
+pub struct SegmentStore {
+    segments: HashMap<String, StoredSegment>,
+    capacity: usize,
+    size: usize,
+    clock: u64,
+    playhead: Option<u64>,
+}
+
+struct StoredSegment {
+    data: Vec<u8>,
+    sequence: Option<u64>,
+    last_access: u64,
+}
+
+impl SegmentStore {
+    pub fn new(capacity: usize) -> Self {
+        SegmentStore {
+            segments: HashMap::new(),
+            capacity,
+            size: 0,
+            clock: 0,
+            playhead: None,
+        }
+    }
+
+    /// Store a segment, evicting others as needed. Returns `false` if the segment alone
+    /// exceeds the store capacity.
+    pub fn insert(&mut self, segment_id: String, data: Vec<u8>) -> bool {
+        if data.len() > self.capacity {
+            return false;
+        }
+        self.remove(&segment_id);
+        while self.size + data.len() > self.capacity {
+            if !self.evict_one() {
+                break;
+            }
+        }
+
+        self.clock += 1;
+        self.size += data.len();
+        let sequence = segment_id.parse().ok();
+        self.segments.insert(
+            segment_id,
+            StoredSegment {
+                data,
+                sequence,
+                last_access: self.clock,
+            },
+        );
+        true
+    }
+
+    /// Get a segment, marking it as recently used.
+    pub fn get(&mut self, segment_id: &str) -> Option<&[u8]> {
+        self.clock += 1;
+        let segment = self.segments.get_mut(segment_id)?;
+        segment.last_access = self.clock;
+        Some(&segment.data)
+    }
+
+    pub fn remove(&mut self, segment_id: &str) -> Option<Vec<u8>> {
+        let segment = self.segments.remove(segment_id)?;
+        self.size -= segment.data.len();
+        Some(segment.data)
+    }
+
+    /// Update the playback position with the sequence number of the segment being played.
+    pub fn set_playhead(&mut self, sequence: u64) {
+        self.playhead = Some(sequence);
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.segments.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Total size in bytes of the stored segments.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn evict_one(&mut self) -> bool {
+        let playhead = self.playhead;
+        let behind_playhead = self
+            .segments
+            .iter()
+            .filter_map(|(id, s)| match (s.sequence, playhead) {
+                (Some(sequence), Some(playhead)) if sequence < playhead => Some((id, sequence)),
+                _ => None,
+            })
+            .min_by_key(|(_, sequence)| *sequence)
+            .map(|(id, _)| id.clone());
+
+        let victim = behind_playhead.or_else(|| {
+            self.segments
+                .iter()
+                .min_by_key(|(_, s)| s.last_access)
+                .map(|(id, _)| id.clone())
+        });
+
+        match victim {
+            Some(segment_id) => {
+                tracing::debug!("Evicting segment {:?} from the store", segment_id);
+                self.remove(&segment_id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(store: &SegmentStore) -> Vec<&str> {
+        let mut ids: Vec<&str> = store.ids().collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn evicts_farthest_behind_playhead_first() {
+        let mut store = SegmentStore::new(30);
+        for id in ["10", "11", "12"] {
+            assert!(store.insert(id.to_string(), vec![0; 10]));
+        }
+        store.set_playhead(12);
+        // The most recently used segment goes first when it is the farthest behind.
+        store.get("10");
+
+        assert!(store.insert("13".to_string(), vec![0; 10]));
+        assert_eq!(ids(&store), ["11", "12", "13"]);
+        assert!(store.insert("14".to_string(), vec![0; 10]));
+        assert_eq!(ids(&store), ["12", "13", "14"]);
+    }
+
+    #[test]
+    fn falls_back_to_least_recently_used() {
+        let mut store = SegmentStore::new(30);
+        for id in ["init", "10", "11"] {
+            assert!(store.insert(id.to_string(), vec![0; 10]));
+        }
+        // Nothing is behind the playhead, and ids that are not sequence numbers never are.
+        store.set_playhead(10);
+        store.get("init");
+
+        assert!(store.insert("12".to_string(), vec![0; 10]));
+        assert_eq!(ids(&store), ["11", "12", "init"]);
+    }
+
+    #[test]
+    fn rejects_segments_larger_than_the_capacity() {
+        let mut store = SegmentStore::new(10);
+        assert!(store.insert("1".to_string(), vec![0; 10]));
+
+        assert!(!store.insert("2".to_string(), vec![0; 11]));
+        assert_eq!(ids(&store), ["1"]);
+        assert_eq!(store.size(), 10);
+    }
+
+    #[test]
+    fn tracks_the_size_of_stored_segments() {
+        let mut store = SegmentStore::new(100);
+        store.insert("1".to_string(), vec![0; 10]);
+        store.insert("2".to_string(), vec![0; 20]);
+        assert_eq!(store.size(), 30);
+
+        // Replacing a segment accounts for the new data only.
+        store.insert("1".to_string(), vec![0; 5]);
+        assert_eq!(store.size(), 25);
+        assert_eq!(store.len(), 2);
+
+        assert_eq!(store.remove("2"), Some(vec![0; 20]));
+        assert_eq!(store.remove("2"), None);
+        assert_eq!(store.size(), 5);
+
+        // Evicting makes room for exactly what is inserted.
+        store.insert("3".to_string(), vec![0; 95]);
+        assert_eq!(store.size(), 100);
+        store.insert("4".to_string(), vec![0; 50]);
+        assert_eq!(ids(&store), ["4"]);
+        assert_eq!(store.size(), 50);
+    }
+}
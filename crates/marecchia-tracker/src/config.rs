@@ -0,0 +1,471 @@
+use clap::Parser;
+use libp2p::{Multiaddr, relay, rendezvous};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fs,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use super::keypair::DEFAULT_KEY_FILE;
+use super::transport::{check_listen_addr, is_wss};
+
+/// Size of the chunks clients transfer segments in, see `CHUNK_SIZE` in marecchia-core. A
+/// circuit must be able to carry at least one of them.
+const CHUNK_SIZE: u64 = 256 * 1024;
+/// Relayed circuits carry whole segments, the libp2p defaults (128 KiB, 2 minutes) would close
+/// them before the first chunk got through.
+const DEFAULT_MAX_CIRCUIT_BYTES: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_CIRCUIT_DURATION_SECS: u64 = 30 * 60;
+
+/// Command-line flags. Each of them can also be set through the environment variable shown in
+/// `--help`, and takes precedence over the config file.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Rendezvous and relay server of the Marecchia P2P network"
+)]
+struct Cli {
+    /// TOML config file.
+    #[arg(short, long, env = "MARECCHIA_TRACKER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Addresses to listen on, comma separated.
+    #[arg(long, env = "MARECCHIA_TRACKER_LISTEN", value_delimiter = ',')]
+    listen: Option<Vec<Multiaddr>>,
+    /// Protobuf encoded keypair, created on first start.
+    #[arg(long, env = "MARECCHIA_TRACKER_KEY_FILE")]
+    key_file: Option<PathBuf>,
+    /// PEM certificate chain served on /wss listeners.
+    #[arg(long, env = "MARECCHIA_TRACKER_WSS_CERT_FILE")]
+    wss_cert_file: Option<PathBuf>,
+    /// PEM private key of the /wss certificate.
+    #[arg(long, env = "MARECCHIA_TRACKER_WSS_KEY_FILE")]
+    wss_key_file: Option<PathBuf>,
+    /// PEM certificate of /webrtc-direct listeners, created on first start.
+    #[arg(long, env = "MARECCHIA_TRACKER_WEBRTC_CERT_FILE")]
+    webrtc_cert_file: Option<PathBuf>,
+    /// Seconds an idle connection is kept open.
+    #[arg(long, env = "MARECCHIA_TRACKER_IDLE_TIMEOUT")]
+    idle_timeout_secs: Option<u64>,
+    /// Seconds between pings on each connection.
+    #[arg(long, env = "MARECCHIA_TRACKER_PING_INTERVAL")]
+    ping_interval_secs: Option<u64>,
+    /// Shortest registration TTL accepted, in seconds.
+    #[arg(long, env = "MARECCHIA_TRACKER_MIN_TTL")]
+    min_ttl: Option<u64>,
+    /// Longest registration TTL accepted, in seconds.
+    #[arg(long, env = "MARECCHIA_TRACKER_MAX_TTL")]
+    max_ttl: Option<u64>,
+    /// Relay reservations held at the same time.
+    #[arg(long, env = "MARECCHIA_TRACKER_MAX_RESERVATIONS")]
+    max_reservations: Option<usize>,
+    /// Relayed circuits open at the same time.
+    #[arg(long, env = "MARECCHIA_TRACKER_MAX_CIRCUITS")]
+    max_circuits: Option<usize>,
+    /// Serve Prometheus metrics over HTTP.
+    #[arg(long, env = "MARECCHIA_TRACKER_METRICS")]
+    metrics: Option<bool>,
+    /// Address of the HTTP server of /metrics.
+    #[arg(long, env = "MARECCHIA_TRACKER_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+    /// Export traces over OTLP.
+    #[arg(long, env = "MARECCHIA_TRACKER_OTLP")]
+    otlp: Option<bool>,
+    /// OTLP collector endpoint, the exporter default when unset.
+    #[arg(long, env = "MARECCHIA_TRACKER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+}
+
+/// Options of the tracker, read from the config file and overridden by flags.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<Multiaddr>,
+    pub key_file: PathBuf,
+    pub idle_timeout_secs: u64,
+    pub ping_interval_secs: u64,
+    pub wss: WssConfig,
+    pub webrtc: WebRtcConfig,
+    pub rendezvous: RendezvousConfig,
+    pub relay: RelayConfig,
+    pub metrics: MetricsConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+/// Certificate of the `/wss` listeners, required when listening on one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WssConfig {
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRtcConfig {
+    /// Certificate of the `/webrtc-direct` listeners, its hash is part of their addresses.
+    pub cert_file: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendezvousConfig {
+    /// Registrations asking for a shorter TTL are refused, in seconds.
+    pub min_ttl: u64,
+    /// Registrations asking for a longer TTL are refused, in seconds.
+    pub max_ttl: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration_secs: u64,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration_secs: u64,
+    /// Bytes relayed in each direction before a circuit is closed.
+    pub max_circuit_bytes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Serve `GET /metrics` in the Prometheus text format.
+    pub enabled: bool,
+    pub listen: SocketAddr,
+    /// Namespaces labelled by name in the rendezvous metrics, the others share `other`.
+    pub max_namespaces: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// Export traces over OTLP, logs go to stdout either way.
+    pub otlp: bool,
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![
+                "/ip4/0.0.0.0/tcp/8000/ws".parse().expect("valid multiaddr"),
+                "/ip4/0.0.0.0/udp/8000/webrtc-direct"
+                    .parse()
+                    .expect("valid multiaddr"),
+            ],
+            key_file: PathBuf::from(DEFAULT_KEY_FILE),
+            idle_timeout_secs: 5,
+            ping_interval_secs: 1,
+            wss: WssConfig::default(),
+            webrtc: WebRtcConfig::default(),
+            rendezvous: RendezvousConfig::default(),
+            relay: RelayConfig::default(),
+            metrics: MetricsConfig::default(),
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            cert_file: PathBuf::from("webrtc.pem"),
+        }
+    }
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        Self {
+            // Clients register for 60s by default, libp2p refuses anything under 2h.
+            min_ttl: 60,
+            max_ttl: rendezvous::MAX_TTL,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        let relay = relay::Config::default();
+        Self {
+            max_reservations: relay.max_reservations,
+            max_reservations_per_peer: relay.max_reservations_per_peer,
+            reservation_duration_secs: relay.reservation_duration.as_secs(),
+            max_circuits: relay.max_circuits,
+            max_circuits_per_peer: relay.max_circuits_per_peer,
+            max_circuit_duration_secs: DEFAULT_MAX_CIRCUIT_DURATION_SECS,
+            max_circuit_bytes: DEFAULT_MAX_CIRCUIT_BYTES,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 9090)),
+            max_namespaces: 100,
+        }
+    }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp: true,
+            otlp_endpoint: None,
+            service_name: "libp2p".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Build the configuration from the command line, the environment and the config file it
+    /// points to, and check it.
+    pub fn load() -> Result<Self, Box<dyn Error>> {
+        let cli = Cli::parse();
+        let mut config = match &cli.config {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| format!("Cannot read config file {}: {e}", path.display()))?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("Invalid config file {}: {e}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.apply(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, cli: Cli) {
+        if let Some(listen) = cli.listen {
+            self.listen = listen;
+        }
+        if let Some(key_file) = cli.key_file {
+            self.key_file = key_file;
+        }
+        if let Some(cert_file) = cli.wss_cert_file {
+            self.wss.cert_file = Some(cert_file);
+        }
+        if let Some(key_file) = cli.wss_key_file {
+            self.wss.key_file = Some(key_file);
+        }
+        if let Some(cert_file) = cli.webrtc_cert_file {
+            self.webrtc.cert_file = cert_file;
+        }
+        if let Some(secs) = cli.idle_timeout_secs {
+            self.idle_timeout_secs = secs;
+        }
+        if let Some(secs) = cli.ping_interval_secs {
+            self.ping_interval_secs = secs;
+        }
+        if let Some(ttl) = cli.min_ttl {
+            self.rendezvous.min_ttl = ttl;
+        }
+        if let Some(ttl) = cli.max_ttl {
+            self.rendezvous.max_ttl = ttl;
+        }
+        if let Some(max) = cli.max_reservations {
+            self.relay.max_reservations = max;
+        }
+        if let Some(max) = cli.max_circuits {
+            self.relay.max_circuits = max;
+        }
+        if let Some(enabled) = cli.metrics {
+            self.metrics.enabled = enabled;
+        }
+        if let Some(listen) = cli.metrics_listen {
+            self.metrics.listen = listen;
+        }
+        if let Some(otlp) = cli.otlp {
+            self.telemetry.otlp = otlp;
+        }
+        if let Some(endpoint) = cli.otlp_endpoint {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.listen.is_empty() {
+            return Err("listen: at least one address is required".to_string());
+        }
+        for addr in &self.listen {
+            check_listen_addr(addr)?;
+        }
+        if let Some(addr) = self.listen.iter().find(|addr| is_wss(addr))
+            && (self.wss.cert_file.is_none() || self.wss.key_file.is_none())
+        {
+            return Err(format!(
+                "listen: {addr}: wss.cert_file and wss.key_file are required to listen on /wss"
+            ));
+        }
+        if self.idle_timeout_secs == 0 {
+            return Err("idle_timeout_secs: must be at least 1".to_string());
+        }
+        if self.ping_interval_secs == 0 {
+            return Err("ping_interval_secs: must be at least 1".to_string());
+        }
+        let RendezvousConfig { min_ttl, max_ttl } = self.rendezvous;
+        if min_ttl == 0 || min_ttl > max_ttl {
+            return Err(format!(
+                "rendezvous: min_ttl ({min_ttl}) must be at least 1 and not above max_ttl ({max_ttl})"
+            ));
+        }
+        let relay = &self.relay;
+        for (name, value) in [
+            ("max_reservations", relay.max_reservations as u64),
+            (
+                "max_reservations_per_peer",
+                relay.max_reservations_per_peer as u64,
+            ),
+            ("reservation_duration_secs", relay.reservation_duration_secs),
+            ("max_circuits", relay.max_circuits as u64),
+            ("max_circuits_per_peer", relay.max_circuits_per_peer as u64),
+            ("max_circuit_duration_secs", relay.max_circuit_duration_secs),
+            ("max_circuit_bytes", relay.max_circuit_bytes),
+        ] {
+            if value == 0 {
+                return Err(format!("relay.{name}: must be at least 1"));
+            }
+        }
+        if relay.max_circuit_bytes < CHUNK_SIZE {
+            return Err(format!(
+                "relay.max_circuit_bytes: must be at least {CHUNK_SIZE}, the size of a chunk"
+            ));
+        }
+        if relay.max_reservations_per_peer > relay.max_reservations {
+            return Err("relay: max_reservations_per_peer is above max_reservations".to_string());
+        }
+        if relay.max_circuits_per_peer > relay.max_circuits {
+            return Err("relay: max_circuits_per_peer is above max_circuits".to_string());
+        }
+        if self.metrics.max_namespaces == 0 {
+            return Err("metrics.max_namespaces: must be at least 1".to_string());
+        }
+        if self.telemetry.service_name.is_empty() {
+            return Err("telemetry.service_name: must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn rendezvous_config(&self) -> rendezvous::server::Config {
+        rendezvous::server::Config::default()
+            .with_min_ttl(self.rendezvous.min_ttl)
+            .with_max_ttl(self.rendezvous.max_ttl)
+    }
+
+    /// Relay limits, keeping the default rate limiters.
+    pub fn relay_config(&self) -> relay::Config {
+        let relay = &self.relay;
+        relay::Config {
+            max_reservations: relay.max_reservations,
+            max_reservations_per_peer: relay.max_reservations_per_peer,
+            reservation_duration: Duration::from_secs(relay.reservation_duration_secs),
+            max_circuits: relay.max_circuits,
+            max_circuits_per_peer: relay.max_circuits_per_peer,
+            max_circuit_duration: Duration::from_secs(relay.max_circuit_duration_secs),
+            max_circuit_bytes: relay.max_circuit_bytes,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The option named by the validation error of `config`.
+    fn invalid_option(config: Config) -> String {
+        let error = config.validate().expect_err("config should be invalid");
+        error.split(':').next().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn accepts_the_defaults_and_the_example() {
+        Config::default().validate().unwrap();
+        let example: Config = toml::from_str(include_str!("../tracker.example.toml")).unwrap();
+        example.validate().unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert!(toml::from_str::<Config>("listen_on = []").is_err());
+        assert!(toml::from_str::<Config>("[relay]\nmax_circuit_size = 1").is_err());
+    }
+
+    #[test]
+    fn rejects_listeners_it_cannot_serve() {
+        let config = Config {
+            listen: Vec::new(),
+            ..Config::default()
+        };
+        assert_eq!(invalid_option(config), "listen");
+
+        let config = Config {
+            listen: vec![
+                "/ip4/0.0.0.0/udp/8000/quic-v1/webtransport"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(invalid_option(config), "listen");
+
+        let mut config = Config {
+            listen: vec!["/ip4/0.0.0.0/tcp/443/wss".parse().unwrap()],
+            ..Config::default()
+        };
+        config.wss.cert_file = Some(PathBuf::from("cert.pem"));
+        assert_eq!(invalid_option(config), "listen");
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let config = Config {
+            idle_timeout_secs: 0,
+            ..Config::default()
+        };
+        assert_eq!(invalid_option(config), "idle_timeout_secs");
+
+        let mut config = Config::default();
+        config.rendezvous.min_ttl = config.rendezvous.max_ttl + 1;
+        assert_eq!(invalid_option(config), "rendezvous");
+
+        let mut config = Config::default();
+        config.metrics.max_namespaces = 0;
+        assert_eq!(invalid_option(config), "metrics.max_namespaces");
+    }
+
+    #[test]
+    fn rejects_relay_limits_too_low_for_segments() {
+        let mut config = Config::default();
+        config.relay.max_circuits = 0;
+        assert_eq!(invalid_option(config), "relay.max_circuits");
+
+        let mut config = Config::default();
+        config.relay.max_circuit_bytes = CHUNK_SIZE - 1;
+        assert_eq!(invalid_option(config), "relay.max_circuit_bytes");
+        let mut config = Config::default();
+        config.relay.max_circuit_bytes = CHUNK_SIZE;
+        config.validate().unwrap();
+
+        let mut config = Config::default();
+        config.relay.max_circuits_per_peer = config.relay.max_circuits + 1;
+        assert_eq!(invalid_option(config), "relay");
+    }
+}
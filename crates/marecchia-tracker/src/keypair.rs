@@ -0,0 +1,33 @@
+use libp2p::identity::Keypair;
+use std::{error::Error, path::Path};
+
+use super::secret::load_or_create_secret;
+
+/// Hex encoded protobuf keypair, takes precedence over the key file.
+pub const KEY_ENV: &str = "MARECCHIA_TRACKER_KEY";
+/// Default path of the protobuf encoded keypair, created on first start.
+pub const DEFAULT_KEY_FILE: &str = "tracker.key";
+
+/// Load the tracker identity from `MARECCHIA_TRACKER_KEY`, or from `key_file`, generating and
+/// persisting a new ed25519 keypair there if it does not exist yet.
+pub fn load_keypair(key_file: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if let Ok(key) = std::env::var(KEY_ENV) {
+        let bytes =
+            hex::decode(key.trim()).map_err(|e| format!("{KEY_ENV} is not valid hex: {e}"))?;
+        return Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|e| format!("{KEY_ENV} is not a protobuf encoded keypair: {e}").into());
+    }
+    load_or_create_secret(
+        key_file,
+        "key file",
+        |bytes| {
+            Keypair::from_protobuf_encoding(bytes)
+                .map_err(|e| format!("not a protobuf encoded keypair: {e}"))
+        },
+        || {
+            let keypair = Keypair::generate_ed25519();
+            let bytes = keypair.to_protobuf_encoding()?;
+            Ok((keypair, bytes))
+        },
+    )
+}
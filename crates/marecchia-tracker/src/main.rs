@@ -1,78 +1,116 @@
 use libp2p::{
-    core::Multiaddr,
+    Transport, autonat,
+    core::{muxing::StreamMuxerBox, upgrade::Version},
+    dns,
     futures::StreamExt,
-    identify, identity,
+    identify,
     metrics::{Metrics, Recorder},
-    multiaddr::Protocol,
     noise, ping, relay, rendezvous,
     swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux,
+    tcp, websocket, yamux,
 };
 use libp2p_metrics::Registry;
+use libp2p_webrtc as webrtc;
 use opentelemetry::{KeyValue, trace::TracerProvider as _};
-use opentelemetry_otlp::SpanExporter;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
 use opentelemetry_sdk::trace::SdkTracerProvider;
-use sha3::{Digest, Sha3_512};
-use std::{error::Error, net::Ipv4Addr, time::Duration};
-use tokio::signal::unix::SignalKind;
+use std::{error::Error, sync::Arc};
+use tokio::{net::TcpListener, signal::unix::SignalKind};
 use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
 
+mod config;
+mod keypair;
+mod metrics;
+mod secret;
+mod transport;
+
+use config::{Config, TelemetryConfig};
+use metrics::{RendezvousMetrics, TrackerMetrics};
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    setup_tracing()?;
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            std::process::exit(2);
+        }
+    };
+    setup_tracing(&config.telemetry)?;
     let mut metric_registry = Registry::default();
 
-    // Results in PeerID 12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN which is
-    // used as the rendezvous point by the other peer examples.
-    let keypair = libp2p::identity::Keypair::ed25519_from_bytes([0; 32])?;
+    let keypair = keypair::load_keypair(&config.key_file)?;
     let tracker_id = keypair.public().to_peer_id();
+    // Printed regardless of the log level, clients need it in the tracker address.
+    println!("Tracker PeerId: {tracker_id}");
 
-    let digest = Sha3_512::digest(&tracker_id.to_bytes());
+    let wss_config = match (&config.wss.cert_file, &config.wss.key_file) {
+        (Some(cert_file), Some(key_file)) => Some(transport::load_wss_config(cert_file, key_file)?),
+        _ => None,
+    };
+    let webrtc_certificate = transport::load_webrtc_certificate(&config.webrtc.cert_file)?;
 
     let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
         .with_tokio()
-        //.with_tcp(
-        //    tcp::Config::default(),
-        //    noise::Config::new,
-        //    yamux::Config::default,
-        //)?
-        .with_websocket(
-            |key: &_| noise::Config::new(key),
-            || yamux::Config::default(),
-        )
-        .await?
+        // Browsers can only dial WebSocket and WebRTC, the same transport serves /ws and /wss.
+        .with_other_transport(|key| {
+            let tcp =
+                dns::tokio::Transport::system(tcp::tokio::Transport::new(tcp::Config::default()))?;
+            let mut ws = websocket::Config::new(tcp);
+            if let Some(tls) = wss_config {
+                ws.set_tls_config(tls);
+            }
+            Ok::<_, Box<dyn Error + Send + Sync>>(
+                ws.upgrade(Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default())
+                    .boxed(),
+            )
+        })?
+        .with_other_transport(|key| {
+            webrtc::tokio::Transport::new(key.clone(), webrtc_certificate)
+                .map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+        })?
         .with_bandwidth_metrics(&mut metric_registry)
         .with_behaviour(|key| SwarmBehaviour {
             identify: identify::Behaviour::new(identify::Config::new(
                 "/marecchia-identify/0.0.1".to_string(),
                 key.public(),
             )),
-            rendezvous: rendezvous::server::Behaviour::new(rendezvous::server::Config::default()),
-            relay: relay::Behaviour::new(tracker_id, relay::Config::default()),
-            ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
+            rendezvous: rendezvous::server::Behaviour::new(config.rendezvous_config()),
+            relay: relay::Behaviour::new(tracker_id, config.relay_config()),
+            // Lets the browser peers learn whether they are reachable.
+            autonat: autonat::Behaviour::new(tracker_id, autonat::Config::default()),
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(config.ping_interval())),
         })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(5)))
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(config.idle_timeout()))
         .build();
 
     let metrics = Metrics::new(&mut metric_registry);
+    let mut tracker_metrics =
+        TrackerMetrics::new(&mut metric_registry, config.metrics.max_namespaces);
+    if config.metrics.enabled {
+        let listener = TcpListener::bind(config.metrics.listen)
+            .await
+            .map_err(|e| format!("Cannot serve metrics on {}: {e}", config.metrics.listen))?;
+        tracing::info!(
+            "Serving metrics on http://{}/metrics",
+            config.metrics.listen
+        );
+        tokio::spawn(metrics::serve(listener, Arc::new(metric_registry)));
+    }
 
-    let listen_addr = Multiaddr::empty()
-        .with(Protocol::Ip4(Ipv4Addr::UNSPECIFIED))
-        //.with(Protocol::Ip6(Ipv6Addr::UNSPECIFpIED))
-        //.with(Protocol::Tcp(25565))
-        .with(Protocol::Udp(25565))
-        .with(Protocol::QuicV1);
-    //.with(Protocol::WebTransport);
-    //.with(Protocol::Certhash(Multihash::<64>::default()))
-    //.with(Protocol::P2p(rendezvous_id));
-
-    swarm.listen_on(listen_addr)?;
+    for addr in &config.listen {
+        swarm
+            .listen_on(addr.clone())
+            .map_err(|e| format!("Cannot listen on {addr}: {e}"))?;
+    }
 
     let mut sigint = tokio::signal::unix::signal(SignalKind::interrupt())?;
     let mut sigterm = tokio::signal::unix::signal(SignalKind::terminate())?;
 
     tokio::select! {
-        _ = rendezvous_loop(&mut swarm, &metrics) => {}
+        _ = rendezvous_loop(&mut swarm, &metrics, &mut tracker_metrics) => {}
         _ = sigint.recv() => {
             tracing::info!("Received SIGINT, shutting down...");
         }
@@ -84,18 +122,38 @@ async fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn rendezvous_loop(swarm: &mut libp2p::Swarm<SwarmBehaviour>, metrics: &Metrics) {
+async fn rendezvous_loop(
+    swarm: &mut libp2p::Swarm<SwarmBehaviour>,
+    metrics: &Metrics,
+    tracker_metrics: &mut TrackerMetrics,
+) {
     while let Some(event) = swarm.next().await {
         metrics.record(&event);
         match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                // Printed regardless of the log level, these are the addresses to give clients.
+                println!("Listening on {address}/p2p/{}", swarm.local_peer_id());
+            }
             SwarmEvent::ConnectionEstablished { peer_id, .. } => {
                 tracing::info!("Connected to {}", peer_id);
+                tracker_metrics
+                    .connected_peers
+                    .set(swarm.connected_peers().count() as i64);
             }
             SwarmEvent::ConnectionClosed { peer_id, .. } => {
                 tracing::info!("Disconnected from {}", peer_id);
+                tracker_metrics
+                    .connected_peers
+                    .set(swarm.connected_peers().count() as i64);
             }
             SwarmEvent::Behaviour(event) => {
-                handle_behaviour_event(swarm, event).await;
+                match &event {
+                    ComposedSwarmEvent::Identify(event) => metrics.record(event),
+                    ComposedSwarmEvent::Relay(event) => metrics.record(event),
+                    ComposedSwarmEvent::Ping(event) => metrics.record(event),
+                    _ => {}
+                }
+                handle_behaviour_event(swarm, tracker_metrics, event).await;
             }
             other => {
                 tracing::debug!("Unhandled {:?}", other);
@@ -106,6 +164,7 @@ async fn rendezvous_loop(swarm: &mut libp2p::Swarm<SwarmBehaviour>, metrics: &Me
 
 async fn handle_behaviour_event(
     swarm: &mut libp2p::Swarm<SwarmBehaviour>,
+    tracker_metrics: &mut TrackerMetrics,
     event: ComposedSwarmEvent,
 ) {
     match event {
@@ -113,14 +172,17 @@ async fn handle_behaviour_event(
             handle_identify_event(swarm, event).await;
         }
         ComposedSwarmEvent::Rendezvous(event) => {
-            handle_rendezvous_event(swarm, event).await;
+            handle_rendezvous_event(swarm, &mut tracker_metrics.rendezvous, event).await;
         }
         ComposedSwarmEvent::Relay(event) => {
-            handle_relay_event(swarm, event).await;
+            handle_relay_event(swarm, tracker_metrics, event).await;
         }
         ComposedSwarmEvent::Ping(event) => {
             handle_ping_event(swarm, event).await;
         }
+        ComposedSwarmEvent::Autonat(event) => {
+            handle_autonat_event(swarm, event).await;
+        }
     }
 }
 
@@ -174,6 +236,7 @@ async fn handle_identify_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event:
 
 async fn handle_rendezvous_event(
     _swarm: &mut libp2p::Swarm<SwarmBehaviour>,
+    metrics: &mut RendezvousMetrics,
     event: rendezvous::server::Event,
 ) {
     match event {
@@ -183,6 +246,7 @@ async fn handle_rendezvous_event(
                 peer,
                 registration.namespace
             );
+            metrics.on_registered(&registration);
         }
         rendezvous::server::Event::PeerNotRegistered {
             peer,
@@ -195,9 +259,11 @@ async fn handle_rendezvous_event(
                 namespace,
                 error
             );
+            metrics.on_registration_failed(&namespace, error);
         }
         rendezvous::server::Event::PeerUnregistered { peer, namespace } => {
             tracing::info!("Peer {} unregistered from namespace '{}'", peer, namespace);
+            metrics.on_unregistered(peer, &namespace);
         }
         rendezvous::server::Event::DiscoverServed {
             enquirer,
@@ -208,17 +274,24 @@ async fn handle_rendezvous_event(
                 enquirer,
                 registrations.len()
             );
+            metrics.on_discover_served(&registrations);
         }
         rendezvous::server::Event::DiscoverNotServed { enquirer, error } => {
             tracing::info!("Failed to serve peer {}: {:?}", enquirer, error);
+            metrics.on_discover_failed(error);
         }
         rendezvous::server::Event::RegistrationExpired(registration) => {
             tracing::info!("Registration expired: {:?}", registration);
+            metrics.on_expired(&registration);
         }
     }
 }
 
-async fn handle_relay_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: relay::Event) {
+async fn handle_relay_event(
+    _swarm: &mut libp2p::Swarm<SwarmBehaviour>,
+    tracker_metrics: &TrackerMetrics,
+    event: relay::Event,
+) {
     match event {
         relay::Event::ReservationReqAccepted {
             src_peer_id,
@@ -229,6 +302,9 @@ async fn handle_relay_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: re
                 src_peer_id,
                 renewed
             );
+            if !renewed {
+                tracker_metrics.relay_reservations.inc();
+            }
         }
         relay::Event::ReservationReqDenied {
             src_peer_id,
@@ -249,6 +325,7 @@ async fn handle_relay_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: re
                 src_peer_id,
                 dst_peer_id
             );
+            tracker_metrics.relay_circuits.inc();
         }
         relay::Event::CircuitReqDenied {
             src_peer_id,
@@ -273,9 +350,15 @@ async fn handle_relay_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: re
                 dst_peer_id,
                 error
             );
+            tracker_metrics.relay_circuits.dec();
+        }
+        relay::Event::ReservationClosed { src_peer_id } => {
+            tracing::info!("Reservation closed from {}", src_peer_id);
+            tracker_metrics.relay_reservations.dec();
         }
         relay::Event::ReservationTimedOut { src_peer_id } => {
             tracing::info!("Reservation timed out from {}", src_peer_id);
+            tracker_metrics.relay_reservations.dec();
         }
         _ => {
             tracing::info!("Received deprecated event: {:?}", event);
@@ -283,6 +366,20 @@ async fn handle_relay_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: re
     }
 }
 
+async fn handle_autonat_event(_swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: autonat::Event) {
+    match event {
+        autonat::Event::InboundProbe(event) => {
+            tracing::info!("AutoNAT probe: {:?}", event);
+        }
+        autonat::Event::OutboundProbe(event) => {
+            tracing::debug!("AutoNAT outbound probe: {:?}", event);
+        }
+        autonat::Event::StatusChanged { old, new } => {
+            tracing::info!("NAT status changed from {:?} to {:?}", old, new);
+        }
+    }
+}
+
 async fn handle_ping_event(swarm: &mut libp2p::Swarm<SwarmBehaviour>, event: ping::Event) {
     match event.result {
         Ok(duration) => {
@@ -302,6 +399,7 @@ struct SwarmBehaviour {
     rendezvous: rendezvous::server::Behaviour,
     relay: relay::Behaviour,
     ping: ping::Behaviour,
+    autonat: autonat::Behaviour,
 }
 
 #[derive(Debug)]
@@ -310,6 +408,7 @@ enum ComposedSwarmEvent {
     Rendezvous(rendezvous::server::Event),
     Relay(relay::Event),
     Ping(ping::Event),
+    Autonat(autonat::Event),
 }
 
 impl From<identify::Event> for ComposedSwarmEvent {
@@ -336,21 +435,39 @@ impl From<ping::Event> for ComposedSwarmEvent {
     }
 }
 
-fn setup_tracing() -> Result<(), Box<dyn Error>> {
-    let resource = opentelemetry_sdk::resource::Resource::builder()
-        .with_attribute(KeyValue::new("service.name", "libp2p"))
-        .build();
-    let provider = SdkTracerProvider::builder()
-        .with_batch_exporter(SpanExporter::builder().with_tonic().build()?)
-        .with_resource(resource)
-        .build();
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
-        .with(
+impl From<autonat::Event> for ComposedSwarmEvent {
+    fn from(event: autonat::Event) -> Self {
+        ComposedSwarmEvent::Autonat(event)
+    }
+}
+
+fn setup_tracing(telemetry: &TelemetryConfig) -> Result<(), Box<dyn Error>> {
+    let otlp = if telemetry.otlp {
+        let resource = opentelemetry_sdk::resource::Resource::builder()
+            .with_attribute(KeyValue::new(
+                "service.name",
+                telemetry.service_name.clone(),
+            ))
+            .build();
+        let mut exporter = SpanExporter::builder().with_tonic();
+        if let Some(endpoint) = &telemetry.otlp_endpoint {
+            exporter = exporter.with_endpoint(endpoint);
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter.build()?)
+            .with_resource(resource)
+            .build();
+        Some(
             tracing_opentelemetry::layer()
                 .with_tracer(provider.tracer("libp2p-subscriber"))
                 .with_filter(EnvFilter::from_default_env()),
         )
+    } else {
+        None
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::from_default_env()))
+        .with(otlp)
         .init();
 
     Ok(())
@@ -0,0 +1,379 @@
+use hyper::{Method, Request, Response, StatusCode, body::Incoming, header, service::service_fn};
+use hyper_util::rt::TokioIo;
+use libp2p::{
+    PeerId,
+    rendezvous::{ErrorCode, Namespace, Registration},
+};
+use libp2p_metrics::Registry;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+};
+use std::{collections::HashSet, convert::Infallible, sync::Arc};
+use tokio::net::TcpListener;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Label of the namespaces seen after the first `max_namespaces` ones.
+const OVERFLOW_NAMESPACE: &str = "other";
+
+/// Tracker state exported next to the libp2p metrics.
+#[derive(Debug)]
+pub struct TrackerMetrics {
+    pub connected_peers: Gauge,
+    pub relay_reservations: Gauge,
+    pub relay_circuits: Gauge,
+    pub rendezvous: RendezvousMetrics,
+}
+
+impl TrackerMetrics {
+    /// Namespaces get their own label up to `max_namespaces`, so that clients cannot blow up the
+    /// number of series by registering under random namespaces.
+    pub fn new(registry: &mut Registry, max_namespaces: usize) -> Self {
+        let metrics = Self {
+            connected_peers: Gauge::default(),
+            relay_reservations: Gauge::default(),
+            relay_circuits: Gauge::default(),
+            rendezvous: RendezvousMetrics::new(max_namespaces),
+        };
+        let registry = registry.sub_registry_with_prefix("tracker");
+        registry.register(
+            "connected_peers",
+            "Peers with at least one open connection",
+            metrics.connected_peers.clone(),
+        );
+        registry.register(
+            "relay_reservations",
+            "Relay reservations currently held by peers",
+            metrics.relay_reservations.clone(),
+        );
+        registry.register(
+            "relay_circuits",
+            "Relayed circuits currently open",
+            metrics.relay_circuits.clone(),
+        );
+        metrics
+            .rendezvous
+            .register(registry.sub_registry_with_prefix("rendezvous"));
+        metrics
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NamespaceLabels {
+    namespace: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FailureLabels {
+    namespace: String,
+    error: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ErrorLabels {
+    error: String,
+}
+
+/// Registrations and discoveries served by the rendezvous server, by namespace.
+///
+/// Discover events do not carry the namespace asked for, so requests and their failures are
+/// counted across namespaces and only the returned registrations by namespace.
+#[derive(Debug)]
+pub struct RendezvousMetrics {
+    registrations: Family<NamespaceLabels, Gauge>,
+    registered: Family<NamespaceLabels, Counter>,
+    unregistered: Family<NamespaceLabels, Counter>,
+    expired: Family<NamespaceLabels, Counter>,
+    registration_failures: Family<FailureLabels, Counter>,
+    discover_requests: Counter,
+    discovered: Family<NamespaceLabels, Counter>,
+    discover_failures: Family<ErrorLabels, Counter>,
+    /// Registrations currently held, to tell renewals from new registrations.
+    held: HashSet<(PeerId, Namespace)>,
+    /// Namespaces with their own label.
+    labelled: HashSet<String>,
+    max_namespaces: usize,
+}
+
+impl RendezvousMetrics {
+    fn new(max_namespaces: usize) -> Self {
+        Self {
+            registrations: Family::default(),
+            registered: Family::default(),
+            unregistered: Family::default(),
+            expired: Family::default(),
+            registration_failures: Family::default(),
+            discover_requests: Counter::default(),
+            discovered: Family::default(),
+            discover_failures: Family::default(),
+            held: HashSet::new(),
+            labelled: HashSet::new(),
+            max_namespaces,
+        }
+    }
+
+    fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "registrations",
+            "Registrations currently held",
+            self.registrations.clone(),
+        );
+        registry.register(
+            "registered",
+            "Registrations accepted, renewals included",
+            self.registered.clone(),
+        );
+        registry.register(
+            "unregistered",
+            "Registrations removed by their peer",
+            self.unregistered.clone(),
+        );
+        registry.register(
+            "expired",
+            "Registrations expired without renewal",
+            self.expired.clone(),
+        );
+        registry.register(
+            "registration_failures",
+            "Registrations refused, by error code",
+            self.registration_failures.clone(),
+        );
+        registry.register(
+            "discover_requests",
+            "Discover requests received",
+            self.discover_requests.clone(),
+        );
+        registry.register(
+            "discovered",
+            "Registrations returned by discover requests",
+            self.discovered.clone(),
+        );
+        registry.register(
+            "discover_failures",
+            "Discover requests refused, by error code",
+            self.discover_failures.clone(),
+        );
+    }
+
+    pub fn on_registered(&mut self, registration: &Registration) {
+        let labels = self.assign_labels(&registration.namespace);
+        self.registered.get_or_create(&labels).inc();
+        let key = (
+            registration.record.peer_id(),
+            registration.namespace.clone(),
+        );
+        if self.held.insert(key) {
+            self.registrations.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn on_registration_failed(&mut self, namespace: &Namespace, error: ErrorCode) {
+        let labels = FailureLabels {
+            namespace: self.labels(namespace).namespace,
+            error: format!("{error:?}"),
+        };
+        self.registration_failures.get_or_create(&labels).inc();
+    }
+
+    pub fn on_unregistered(&mut self, peer: PeerId, namespace: &Namespace) {
+        let labels = self.labels(namespace);
+        self.unregistered.get_or_create(&labels).inc();
+        if self.held.remove(&(peer, namespace.clone())) {
+            self.registrations.get_or_create(&labels).dec();
+        }
+    }
+
+    pub fn on_expired(&mut self, registration: &Registration) {
+        let labels = self.labels(&registration.namespace);
+        self.expired.get_or_create(&labels).inc();
+        let key = (
+            registration.record.peer_id(),
+            registration.namespace.clone(),
+        );
+        if self.held.remove(&key) {
+            self.registrations.get_or_create(&labels).dec();
+        }
+    }
+
+    pub fn on_discover_served(&mut self, registrations: &[Registration]) {
+        self.discover_requests.inc();
+        for registration in registrations {
+            let labels = self.labels(&registration.namespace);
+            self.discovered.get_or_create(&labels).inc();
+        }
+    }
+
+    pub fn on_discover_failed(&mut self, error: ErrorCode) {
+        self.discover_requests.inc();
+        let labels = ErrorLabels {
+            error: format!("{error:?}"),
+        };
+        self.discover_failures.get_or_create(&labels).inc();
+    }
+
+    /// Label of a namespace that registered successfully, giving it its own label while
+    /// there is room. Once a namespace got its own label it keeps it.
+    fn assign_labels(&mut self, namespace: &Namespace) -> NamespaceLabels {
+        if self.labelled.len() < self.max_namespaces {
+            self.labelled.insert(namespace.to_string());
+        }
+        self.labels(namespace)
+    }
+
+    /// Label of `namespace`. Only registrations hand out labels, so that failed requests
+    /// cannot use up the labels of the namespaces actually in use.
+    fn labels(&self, namespace: &Namespace) -> NamespaceLabels {
+        let namespace = namespace.to_string();
+        if self.labelled.contains(&namespace) {
+            return NamespaceLabels { namespace };
+        }
+        NamespaceLabels {
+            namespace: OVERFLOW_NAMESPACE.to_string(),
+        }
+    }
+}
+
+/// Serve `GET /metrics` in the Prometheus text format on `listener`, until the process exits.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::warn!("Failed to accept a metrics connection: {:?}", e);
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let service = service_fn(|request| {
+                let response = respond(&request, &registry);
+                async move { Ok::<_, Infallible>(response) }
+            });
+            if let Err(e) = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!("Metrics connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+fn respond(request: &Request<Incoming>, registry: &Registry) -> Response<String> {
+    let status = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut body = String::new();
+            if let Err(e) = encode(&mut body, registry) {
+                tracing::error!("Failed to encode metrics: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                return Response::builder()
+                    .header(header::CONTENT_TYPE, CONTENT_TYPE)
+                    .body(body)
+                    .expect("valid response");
+            }
+        }
+        (_, "/metrics") => StatusCode::METHOD_NOT_ALLOWED,
+        _ => StatusCode::NOT_FOUND,
+    };
+    let mut response = Response::new(String::new());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{core::PeerRecord, identity::Keypair};
+
+    fn registration(keypair: &Keypair, namespace: &str) -> Registration {
+        Registration {
+            namespace: Namespace::new(namespace.to_string()).unwrap(),
+            record: PeerRecord::new(keypair, Vec::new()).unwrap(),
+            ttl: 60,
+        }
+    }
+
+    fn labels(namespace: &str) -> NamespaceLabels {
+        NamespaceLabels {
+            namespace: namespace.to_string(),
+        }
+    }
+
+    fn held(metrics: &RendezvousMetrics, namespace: &str) -> i64 {
+        metrics
+            .registrations
+            .get_or_create(&labels(namespace))
+            .get()
+    }
+
+    #[test]
+    fn counts_renewals_without_holding_twice() {
+        let mut metrics = RendezvousMetrics::new(10);
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        metrics.on_registered(&registration(&alice, "stream"));
+        metrics.on_registered(&registration(&alice, "stream"));
+        metrics.on_registered(&registration(&bob, "stream"));
+
+        assert_eq!(held(&metrics, "stream"), 2);
+        assert_eq!(metrics.registered.get_or_create(&labels("stream")).get(), 3);
+    }
+
+    #[test]
+    fn releases_unregistered_and_expired_registrations() {
+        let mut metrics = RendezvousMetrics::new(10);
+        let (alice, bob) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let namespace = Namespace::from_static("stream");
+        metrics.on_registered(&registration(&alice, "stream"));
+        metrics.on_registered(&registration(&bob, "stream"));
+
+        metrics.on_unregistered(alice.public().to_peer_id(), &namespace);
+        assert_eq!(held(&metrics, "stream"), 1);
+        // Unregistering again does not hold less than nothing.
+        metrics.on_unregistered(alice.public().to_peer_id(), &namespace);
+        assert_eq!(held(&metrics, "stream"), 1);
+
+        metrics.on_expired(&registration(&bob, "stream"));
+        assert_eq!(held(&metrics, "stream"), 0);
+        assert_eq!(
+            metrics.unregistered.get_or_create(&labels("stream")).get(),
+            2
+        );
+        assert_eq!(metrics.expired.get_or_create(&labels("stream")).get(), 1);
+    }
+
+    #[test]
+    fn caps_the_labelled_namespaces() {
+        let mut metrics = RendezvousMetrics::new(2);
+        let keypair = Keypair::generate_ed25519();
+        for namespace in ["a", "b", "c", "d"] {
+            metrics.on_registered(&registration(&keypair, namespace));
+        }
+        assert_eq!(held(&metrics, "a"), 1);
+        assert_eq!(held(&metrics, "b"), 1);
+        assert_eq!(held(&metrics, OVERFLOW_NAMESPACE), 2);
+
+        // Namespaces keep their label once they got one.
+        metrics.on_unregistered(keypair.public().to_peer_id(), &Namespace::from_static("a"));
+        assert_eq!(held(&metrics, "a"), 0);
+        metrics.on_registered(&registration(&keypair, "a"));
+        assert_eq!(held(&metrics, "a"), 1);
+    }
+
+    #[test]
+    fn failed_registrations_do_not_use_up_labels() {
+        let mut metrics = RendezvousMetrics::new(1);
+        metrics.on_registration_failed(&Namespace::from_static("spam"), ErrorCode::InvalidTtl);
+        metrics.on_registered(&registration(&Keypair::generate_ed25519(), "stream"));
+
+        assert_eq!(held(&metrics, "stream"), 1);
+        let failure = FailureLabels {
+            namespace: OVERFLOW_NAMESPACE.to_string(),
+            error: "InvalidTtl".to_string(),
+        };
+        assert_eq!(
+            metrics.registration_failures.get_or_create(&failure).get(),
+            1
+        );
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    error::Error,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+/// Load the secret stored in `path`, or generate one and persist it there if the file does not
+/// exist yet. `generate` returns the secret together with the bytes to store, that `parse`
+/// reads back on the next start. `kind` names the file in errors and logs.
+pub fn load_or_create_secret<T>(
+    path: &Path,
+    kind: &str,
+    parse: impl FnOnce(&[u8]) -> Result<T, String>,
+    generate: impl FnOnce() -> Result<(T, Vec<u8>), Box<dyn Error>>,
+) -> Result<T, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            parse(&bytes).map_err(|e| format!("Invalid {kind} {}: {e}", path.display()).into())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let (secret, bytes) = generate()?;
+            // Only the tracker may read its secrets.
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)
+                .map_err(|e| format!("Cannot create {kind} {}: {e}", path.display()))?;
+            file.write_all(&bytes)?;
+            tracing::info!("Generated a new {kind} in {}", path.display());
+            Ok(secret)
+        }
+        Err(e) => Err(format!("Cannot read {kind} {}: {e}", path.display()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn load(path: &Path, generated: &str) -> Result<String, Box<dyn Error>> {
+        load_or_create_secret(
+            path,
+            "test secret",
+            |bytes| String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string()),
+            || Ok((generated.to_string(), generated.as_bytes().to_vec())),
+        )
+    }
+
+    #[test]
+    fn creates_the_secret_once_readable_by_the_owner_only() {
+        let dir = std::env::temp_dir().join(format!("marecchia-secret-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("secret");
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(load(&path, "first").unwrap(), "first");
+        assert_eq!(load(&path, "second").unwrap(), "first");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::write(&path, [0xff]).unwrap();
+        assert!(load(&path, "third").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
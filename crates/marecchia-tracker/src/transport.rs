@@ -0,0 +1,62 @@
+use libp2p::{Multiaddr, multiaddr::Protocol, websocket::tls};
+use libp2p_webrtc::tokio::Certificate;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::{error::Error, path::Path};
+
+use super::secret::load_or_create_secret;
+
+/// Whether browsers reach `addr` over secure WebSocket.
+pub fn is_wss(addr: &Multiaddr) -> bool {
+    addr.iter()
+        .any(|protocol| matches!(protocol, Protocol::Wss(_) | Protocol::Tls))
+}
+
+/// Reject listen addresses the tracker cannot serve, with the reason.
+pub fn check_listen_addr(addr: &Multiaddr) -> Result<(), String> {
+    if addr
+        .iter()
+        .any(|protocol| matches!(protocol, Protocol::WebTransport))
+    {
+        return Err(format!(
+            "listen: {addr}: rust-libp2p has no WebTransport server, browsers can dial /webrtc-direct instead"
+        ));
+    }
+    Ok(())
+}
+
+/// Server TLS config for `/wss` listeners, from a PEM certificate chain and private key.
+pub fn load_wss_config(cert_file: &Path, key_file: &Path) -> Result<tls::Config, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Cannot read certificates from {}: {e}", cert_file.display()))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", cert_file.display()).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| format!("Cannot read private key from {}: {e}", key_file.display()))?;
+    let certs = certs
+        .into_iter()
+        .map(|cert| tls::Certificate::new(cert.to_vec()));
+    tls::Config::new(tls::PrivateKey::new(key.secret_der().to_vec()), certs)
+        .map_err(|e| format!("Invalid TLS certificate or key: {e}").into())
+}
+
+/// Load the WebRTC certificate from `path`, generating and persisting a new one if it does not
+/// exist yet. Its hash is part of the `/webrtc-direct` addresses given to clients, so it must
+/// not change across restarts.
+pub fn load_webrtc_certificate(path: &Path) -> Result<Certificate, Box<dyn Error>> {
+    // The PEM holds the private key as well.
+    load_or_create_secret(
+        path,
+        "certificate file",
+        |bytes| {
+            let pem = std::str::from_utf8(bytes).map_err(|e| format!("not UTF-8: {e}"))?;
+            Certificate::from_pem(pem).map_err(|e| format!("not a PEM encoded certificate: {e}"))
+        },
+        || {
+            let certificate = Certificate::generate(&mut rand::thread_rng())?;
+            let pem = certificate.serialize_pem().into_bytes();
+            Ok((certificate, pem))
+        },
+    )
+}
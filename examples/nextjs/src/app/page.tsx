@@ -1,11 +1,22 @@
 import Video from "@/components/video";
 import Image from "next/image";
 
+// Address of the Marecchia tracker, ending with the peer ID it prints on startup
+const TRACKER: string = (() => {
+  const tracker = process.env.NEXT_PUBLIC_MARECCHIA_TRACKER;
+  if (!tracker) {
+    throw new Error(
+      "NEXT_PUBLIC_MARECCHIA_TRACKER is not set: set it to the address of your tracker, e.g. /dns/tracker.example.com/tcp/443/wss/p2p/<tracker peer ID>",
+    );
+  }
+  return tracker;
+})();
+
 export default function Home() {
   return (
     <div>
       <h1>Home</h1>
-      <Video src="https://devstreaming-cdn.apple.com/videos/streaming/examples/img_bipbop_adv_example_fmp4/master.m3u8"/>
+      <Video src="https://devstreaming-cdn.apple.com/videos/streaming/examples/img_bipbop_adv_example_fmp4/master.m3u8" tracker={TRACKER}/>
     </div>
   );
 }
@@ -1,10 +1,11 @@
 "use client";
 import { useEffect, useRef } from "react";
 import Hls, { HlsConfig } from "hls.js";
-import init, { p2pFragmentLoader } from "@marecchia/hlsjs";
+import init, { p2pFragmentLoader, P2PConfig } from "@marecchia/hlsjs";
 
 export type VideoProps = {
     src: string;
+    tracker: string;
 };
 
 export default function Video(props: VideoProps) {
@@ -21,7 +22,9 @@ export default function Video(props: VideoProps) {
                 return;
             }
 
-            const fLoader = p2pFragmentLoader(props.src);
+            const p2pConfig = new P2PConfig();
+            p2pConfig.add_tracker(props.tracker);
+            const fLoader = p2pFragmentLoader(props.src, p2pConfig);
             console.log(new fLoader(Hls.DefaultConfig));
             const hls = new Hls({
                 //debug: process.env.NODE_ENV === "development",
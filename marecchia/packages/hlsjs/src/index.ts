@@ -1,43 +1,56 @@
 import Hls, { FragmentLoaderConstructor, FragmentLoaderContext, HlsConfig, LoadStats, Loader, LoaderCallbacks, LoaderConfiguration, LoaderContext, LoaderStats } from "hls.js";
-import init, { new_p2p_client, P2PClient } from "@marecchia/marecchia-core";
+import init, { new_p2p_client, P2PClient, P2PConfig } from "@marecchia/marecchia-core";
 
 export default init;
-export function p2pFragmentLoader(stream_id: string): FragmentLoaderConstructor {
+
+const P2P_REQUEST_TIMEOUT_MS = 5000;
+export { P2PConfig };
+export function p2pFragmentLoader(stream_id: string, p2pConfig: P2PConfig): FragmentLoaderConstructor {
     return class P2PFragmentLoader implements Loader<FragmentLoaderContext> {
         private p2pNetwork: P2PClient;
         private httpLoader: (context: LoaderContext, config: LoaderConfiguration, callbacks: LoaderCallbacks<LoaderContext>) => void;
+        private requestId: number | null;
+        private aborted: boolean;
         context: FragmentLoaderContext | null;
         stats: LoaderStats;
 
         constructor(confg: HlsConfig) {
-            this.p2pNetwork = new_p2p_client(stream_id);
+            this.p2pNetwork = new_p2p_client(stream_id, p2pConfig);
             this.httpLoader = new Hls.DefaultConfig.loader(confg).load;
             this.stats = new LoadStats();
             this.context = null;
+            this.requestId = null;
+            this.aborted = false;
         }
         load(context: FragmentLoaderContext, config: LoaderConfiguration, callbacks: LoaderCallbacks<FragmentLoaderContext>): void {
             const segmentId = context.frag.sn.toString();
+            this.aborted = false;
 
             // P2P exchanges only complete segments (no byte range support)
             context.rangeStart = undefined;
             context.rangeEnd = undefined;
 
-            const segment_promise = this.p2pNetwork.request_segment(segmentId);
-            const timeout_promise = new Promise((_resolve: (value: Uint8Array) => void, reject) => {
-                const timeout = setTimeout(() => {
-                    clearTimeout(timeout)
-                    reject(new Error("Segment request timed out"))
-                }, 5000);
-            });
-
-            Promise.race([segment_promise, timeout_promise])
+            // The P2P client expires the request itself after the timeout
+            const requestId = this.p2pNetwork.new_request_id();
+            this.requestId = requestId;
+            this.p2pNetwork.request_segment(segmentId, P2P_REQUEST_TIMEOUT_MS, requestId)
                 .then((segment) => {
+                    if (this.requestId === requestId) {
+                        this.requestId = null;
+                    }
                     callbacks.onSuccess({
                         url: `p2p://${segmentId}`,
                         data: segment,
                     }, this.stats, context, null);
                 })
                 .catch((_) => {
+                    if (this.requestId === requestId) {
+                        this.requestId = null;
+                    }
+                    // An aborted load must not fall back to HTTP either
+                    if (this.aborted) {
+                        return;
+                    }
                     // Custom callbacks to upload a new segment once is downloaded from the server
                     const http_callbacks: LoaderCallbacks<FragmentLoaderContext> = {
                         ...callbacks,
@@ -54,10 +67,15 @@ export function p2pFragmentLoader(stream_id: string): FragmentLoaderConstructor
                 });
         }
         destroy(): void {
-            this.p2pNetwork.quit();
+            // Release the WASM client only once the swarm is torn down
+            const p2pNetwork = this.p2pNetwork;
+            p2pNetwork.quit().finally(() => p2pNetwork.free());
         }
         abort(): void {
-            this.destroy();
+            this.aborted = true;
+            if (this.requestId !== null) {
+                this.p2pNetwork.cancel_request(this.requestId);
+            }
         }
     }
 }